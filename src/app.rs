@@ -4,12 +4,18 @@ use cli_clipboard::ClipboardContext;
 use tokio::sync::Mutex;
 
 use crate::se::User;
+use crate::notify::Notifier;
+use crate::views::Clock;
 
 pub struct App {
     pub status: Status,
     pub clipboard: ClipboardContext,
     pub user: Option<User>,
     pub message: Option<String>,
+    /// How message times are shown.
+    pub clock: Clock,
+    /// How to alert the user when they're pinged.
+    pub notifier: Notifier,
 }
 
 pub type AppRef = Arc<Mutex<App>>;
@@ -1,25 +1,45 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::process::{self, Stdio};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use cli_clipboard::{ClipboardContext, ClipboardProvider};
 use cursive::{Cursive, CursiveExt};
 use cursive::align::{HAlign, VAlign};
-use cursive::event::Key;
+use cursive::theme::Effect;
+use cursive::utils::markup::StyledString;
+use cursive::event::{Event, EventResult, EventTrigger, Key};
 use cursive::traits::{Nameable, Resizable};
-use cursive::view::ScrollStrategy;
-use cursive::views::{Button, Dialog, DummyView, EditView, LinearLayout, ScrollView, TextArea, TextView};
+use cursive::View;
+use cursive::views::{
+    Button, Dialog, DummyView, EditView, HideableView, LayerPosition, LinearLayout, NamedView, OnEventView, Panel,
+    ResizedView, ScrollView, SelectView, TextArea, TextView,
+};
 use cursive_async_view::AsyncView;
-use cursive_markup::MarkupView;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
 use crate::app::{App, AppRef, Status};
-use crate::se::{Room, User};
+use crate::se::markdown;
+use crate::se::{
+    is_too_long, ping_before, ping_completions, reply_prefix, LongMessage, Message, MESSAGE_LIMIT, MessageState, MessageUpdate, Room, RoomSpec,
+    RoomUser, SeError, StarredMessage, Unread, User,
+};
+use crate::notify::Notifier;
+use crate::render::render;
+use crate::views::{Clock, MessageList, MessageView};
 
 mod se;
 mod app;
+mod views;
+mod render;
+mod notify;
 
 fn main() {
     let app = Arc::new(tokio::sync::Mutex::new(
@@ -28,6 +48,8 @@ fn main() {
             clipboard: ClipboardContext::new().unwrap(),
             user: None,
             message: None,
+            clock: Clock::from_env(),
+            notifier: Notifier::from_env(),
         }
     ));
 
@@ -93,7 +115,7 @@ fn main() {
             })
     );
 
-    siv.add_global_callback(Key::Esc, |siv| siv.quit());
+    siv.add_global_callback(Key::Esc, confirm_quit);
 
     siv.run();
 }
@@ -122,10 +144,26 @@ async fn event_thread(
         }
     }
 
+    let connection_state = app.lock().await.user().connection_state().unwrap();
+    let moved_connection_state = connection_state.clone();
+    let moved_cb_sink = cb_sink.clone();
+    let watch_connection = tokio::spawn(async move {
+        let mut connection_state = moved_connection_state;
+        while connection_state.changed().await.is_ok() {
+            let state = connection_state.borrow().to_string();
+            let res = moved_cb_sink.send(Box::new(move |siv| {
+                siv.call_on_name("status", |status: &mut TextView| status.set_content(state));
+            }));
+            if res.is_err() {
+                break;
+            }
+        }
+    });
+
     let moved_to_ui = to_ui.clone();
     let moved_cb_sink = cb_sink.clone();
     let moved_app = app.clone();
-    tokio::spawn(async move {
+    let poll_rooms = tokio::spawn(async move {
         let mut first = true;
         loop {
             let rooms = moved_app.lock().await.user().get_all_rooms().await;
@@ -147,15 +185,24 @@ async fn event_thread(
                                 let moved_app = moved_app.clone();
                                 let moved_from_event = moved_from_event.clone();
                                 let moved_to_event = moved_to_event.clone();
-                                let room_id = room.id;
+                                let room = room.clone();
                                 room_list.add_child(Button::new(room.name.clone(), move |siv| {
-                                    moved_to_event.blocking_send(Command::Join(room_id)).unwrap();
-                                    in_room(
-                                        siv,
-                                        moved_app.clone(),
-                                        moved_from_event.clone(),
-                                        moved_to_event.clone(),
-                                    );
+                                    if siv.find_name::<SelectView<RoomSpec>>("rooms").is_none() {
+                                        in_room(
+                                            siv,
+                                            moved_app.clone(),
+                                            moved_from_event.clone(),
+                                            moved_to_event.clone(),
+                                        );
+                                    } else {
+                                        swap_room_list(siv);
+                                    }
+                                    siv.call_on_name("rooms", |rooms: &mut SelectView<RoomSpec>| {
+                                        if !rooms.iter().any(|(_, joined)| joined.id == room.id) {
+                                            rooms.add_item(room.name.clone(), room.clone());
+                                        }
+                                    });
+                                    switch_room(siv, room.clone());
                                 }));
                             }
                         });
@@ -171,62 +218,463 @@ async fn event_thread(
             sleep(Duration::from_secs(30)).await;
         }
     });
-    let moved_app = app.clone();
-    tokio::spawn(async move {
-        let mut last_count = 0;
-        let last_room = Arc::new(Mutex::new(None));
-        loop {
-            let app = moved_app.lock().await;
-            let room = app.user().current_room();
-            if let Some(room) = room {
-                let messages = room.get_messages().await;
-                let id = Some(room.get_id());
-                drop(app);
-                if last_count < messages.len() || *last_room.lock().unwrap() != id {
-                    last_count = messages.len();
-                    let moved_last_room = last_room.clone();
-                    let res = cb_sink.send(Box::new(move |siv| {
-                        siv.call_on_name("messages", |msgs: &mut LinearLayout| {
-                            moved_last_room.lock().unwrap().replace(id.unwrap());
-                            msgs.clear();
-                            msgs.add_child(DummyView);
-                            for message in messages.iter() {
-                                msgs.add_child(MarkupView::html(
-                                    format!("{}: {}", message.username, message.content).as_str()
-                                ));
-                            }
-                        });
-                    }));
-                    if res.is_err() {
-                        break;
+    // render the current room, replaced whenever another one is joined
+    let mut room_view: Vec<JoinHandle<()>> = Vec::new();
+    let background = [watch_connection, poll_rooms];
+    while let Some(command) = from_ui.recv().await {
+        match command {
+            Command::Copy(text) => {
+                let result = app.lock().await.clipboard.set_contents(text);
+                to_ui.send(
+                    match result {
+                        Ok(_) => Command::Success,
+                        Err(error) => {
+                            let error: Box<dyn Error + Send + Sync> = error.to_string().into();
+                            Command::Error(error)
+                        }
                     }
+                ).await.unwrap();
+            }
+            Command::Join(spec) => {
+                let room_id = spec.id;
+                let joining = {
+                    let app = app.lock().await;
+                    let user = app.user();
+                    match user.get_room(room_id) {
+                        Some(_) => None,
+                        None => Some(user.join_room(room_id)),
+                    }
+                };
+                // the app isn't locked while the room loads, so the rest of the UI keeps working
+                let loaded = match joining {
+                    Some(Ok(joining)) => Some(joining.await),
+                    Some(Err(error)) => Some(Err(error)),
+                    None => None,
+                };
+                let mut app = app.lock().await;
+                let (notifier, clock) = (app.notifier, app.clock);
+                let user = app.user.as_mut().unwrap();
+                let joined = loaded.is_none();
+                let room = match loaded {
+                    Some(Ok(room)) => user.add_room(room).await.clone(),
+                    Some(Err(error)) => {
+                        let current = user.current_room().map(Room::get_id);
+                        let error = error.to_string();
+                        let _ = cb_sink.send(Box::new(move |siv| join_failed(siv, room_id, current, &error)));
+                        continue;
+                    }
+                    None => user.get_room(room_id).unwrap().clone(),
+                };
+                let queued = room.queued();
+                if !joined {
+                    if notifier != Notifier::Off {
+                        tokio::spawn(watch_pings(spec.name.clone(), room.subscribe_pings(), notifier, cb_sink.clone()));
+                    }
+                    tokio::spawn(watch_unread(spec, room.unread(), cb_sink.clone()));
                 }
+                user.set_current_room(room_id);
+                app.status = Status::InRoom;
+                for task in room_view.drain(..) {
+                    task.abort();
+                }
+                room_view.push(tokio::spawn(watch_room(room.clone(), clock, cb_sink.clone())));
+                room_view.push(tokio::spawn(watch_outbox(queued, cb_sink.clone())));
+                room_view.push(tokio::spawn(watch_users(room, cb_sink.clone())));
+                // the status bar is created with the room view, so it missed any earlier updates
+                let state = connection_state.borrow().to_string();
+                cb_sink.send(Box::new(move |siv| {
+                    siv.call_on_name("status", |status: &mut TextView| status.set_content(state));
+                })).unwrap();
             }
-        }
-    });
-
-    while let Some(command) = from_ui.recv().await {
-        match command {
-            Command::Send(message) =>
+            Command::Leave(room_id) => {
+                let mut app = app.lock().await;
+                let user = app.user.as_mut().unwrap();
+                if user.current_room().map(Room::get_id) == Some(room_id) {
+                    for task in room_view.drain(..) {
+                        task.abort();
+                    }
+                }
                 to_ui.send(
-                    match app.lock().await.user().current_room().unwrap().send_message(&message).await {
+                    match user.leave_room(room_id).await {
                         Ok(_) => Command::Success,
                         Err(error) => Command::Error(Box::new(error)),
                     }
-                ).await.unwrap(),
-            Command::Join(room_id) => {
+                ).await.unwrap();
+            }
+            Command::Quit { leave_rooms } => {
                 let mut app = app.lock().await;
-                let mut user = app.user.as_mut().unwrap();
-                user.join_room(room_id).await.unwrap();
-                user.current_room = Some(room_id);
-                app.status = Status::InRoom;
+                app.status = Status::Closing;
+                for task in room_view.iter().chain(background.iter()) {
+                    task.abort();
+                }
+                if let Some(user) = app.user.take() {
+                    // we're quitting either way, so there's nothing to do if a room couldn't be left
+                    let _ = user.close(leave_rooms).await;
+                }
+                let _ = cb_sink.send(Box::new(|siv| siv.quit()));
+                return;
             }
             Command::Success => (),
-            x => unreachable!("{:?}", x),
+            command => {
+                let room = app.lock().await.user().current_room().cloned();
+                match room {
+                    Some(room) => room_command(&room, command, &to_ui, &cb_sink).await,
+                    None if matches!(command, Command::GetLastOwnMessage) =>
+                        to_ui.send(Command::LastOwnMessage(None)).await.unwrap(),
+                    // the room view can still be brought back after leaving the last room
+                    None if command.has_reply() =>
+                        to_ui.send(Command::Error(Box::new(SeError::NotInRoom))).await.unwrap(),
+                    None => {
+                        let _ = cb_sink.send(Box::new(|siv| siv.add_layer(Dialog::info(SeError::NotInRoom.to_string()))));
+                    }
+                }
+            }
         }
     }
 }
 
+/// Handles a command that acts on the current room.
+async fn room_command(room: &Room, command: Command, to_ui: &Sender<Command>, cb_sink: &CbSink) {
+    match command {
+        Command::Send(message, long) => {
+            // the message shows up as pending and then as sent or failed, so nothing waits for it
+            room.send_message(&message, long).await;
+        }
+        Command::Edit(id, message) =>
+            to_ui.send(
+                match room.edit_message(id, &message).await {
+                    Ok(_) => Command::Success,
+                    Err(error) => Command::Error(Box::new(error)),
+                }
+            ).await.unwrap(),
+        Command::Delete(id) =>
+            to_ui.send(
+                match room.delete_message(id).await {
+                    Ok(_) => Command::Success,
+                    Err(error) => Command::Error(Box::new(error)),
+                }
+            ).await.unwrap(),
+        Command::Reply(id, message, long) => {
+            room.reply(id, &message, long).await;
+        }
+        Command::Retry(id) => room.retry(id).await,
+        Command::Discard(id) => room.discard(id).await,
+        Command::GetReplyTarget => {
+            let target = room.last_other_message().await
+                .map(|message| (message.id, quote(&message)));
+            to_ui.send(Command::ReplyTarget(target)).await.unwrap();
+        }
+        Command::ToggleStar(id) =>
+            to_ui.send(
+                match room.toggle_star(id).await {
+                    Ok(_) => Command::Success,
+                    Err(error) => Command::Error(Box::new(error)),
+                }
+            ).await.unwrap(),
+        Command::SetPinned(id, pinned) => {
+            let result = if pinned { room.pin(id).await } else { room.unpin(id).await };
+            to_ui.send(
+                match result {
+                    Ok(_) => Command::Success,
+                    Err(error) => Command::Error(Box::new(error)),
+                }
+            ).await.unwrap();
+        }
+        Command::GetSource(id) =>
+            to_ui.send(
+                match room.get_message_source(id).await {
+                    Ok(source) => Command::Source(source),
+                    Err(error) => Command::Error(Box::new(error)),
+                }
+            ).await.unwrap(),
+        Command::LoadBefore(id) => {
+            let result = room.load_before(id, SCROLLBACK_PAGE).await;
+            // nothing is waiting for a reply, so errors are shown directly
+            if let Err(error) = result {
+                cb_sink.send(Box::new(move |siv| siv.add_layer(Dialog::info(error.to_string())))).unwrap();
+            }
+        }
+        Command::GetUsernames => {
+            let usernames = room.recent_usernames().await;
+            to_ui.send(Command::Usernames(usernames)).await.unwrap();
+        }
+        Command::GetStarboard =>
+            to_ui.send(
+                match room.starred_messages().await {
+                    Ok(starred) => Command::Starboard(starred),
+                    Err(error) => Command::Error(Box::new(error)),
+                }
+            ).await.unwrap(),
+        Command::GetLastOwnMessage => {
+            let last = room.last_own_message().await.filter(Message::is_editable);
+            to_ui.send(Command::LastOwnMessage(last.map(|message| message.id))).await.unwrap();
+        }
+        x => unreachable!("{:?}", x),
+    }
+}
+
+/// Renders the messages of a room, and then keeps them up to date as they are added or change.
+async fn watch_room(room: Room, clock: Clock, cb_sink: CbSink) {
+    let own_id = room.get_user_id();
+    loop {
+        // subscribe first, so nothing is missed between taking the messages and listening
+        let mut updates = room.subscribe();
+        let messages = room.get_messages().await;
+        // replies whose parents aren't known yet are shown straight away and quoted once they are
+        let quotes = known_quotes(&room, &messages).await;
+        let shown = messages.clone();
+        let shown_quotes = quotes.clone();
+        let res = cb_sink.send(Box::new(move |siv| {
+            siv.call_on_name("messages", |msgs: &mut MessageList| {
+                msgs.clear();
+                for message in shown.into_iter() {
+                    msgs.upsert(message_view(message, own_id, clock, &shown_quotes));
+                }
+            });
+        }));
+        if res.is_err() || !fetch_quotes(&room, &messages, &quotes, &cb_sink).await {
+            return;
+        }
+        loop {
+            let message = match updates.recv().await {
+                Ok(MessageUpdate::Changed(message)) => message,
+                Ok(MessageUpdate::Removed(id)) => {
+                    let res = cb_sink.send(Box::new(move |siv| {
+                        siv.call_on_name("messages", |msgs: &mut MessageList| msgs.remove(id));
+                    }));
+                    if res.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                // too far behind to patch the view, so render it all again
+                Err(RecvError::Lagged(_)) => break,
+                Err(RecvError::Closed) => return,
+            };
+            let messages = std::slice::from_ref(&message);
+            let quotes = known_quotes(&room, messages).await;
+            let shown = message.clone();
+            let shown_quotes = quotes.clone();
+            let res = cb_sink.send(Box::new(move |siv| {
+                siv.call_on_name("messages", |msgs: &mut MessageList| {
+                    msgs.upsert(message_view(shown, own_id, clock, &shown_quotes));
+                });
+            }));
+            if res.is_err() || !fetch_quotes(&room, messages, &quotes, &cb_sink).await {
+                return;
+            }
+        }
+    }
+}
+
+/// Keeps a room's entry in the sidebar showing how many unread messages and mentions it has.
+async fn watch_unread(room: RoomSpec, mut unread: watch::Receiver<Unread>, cb_sink: CbSink) {
+    loop {
+        let label = room_label(&room.name, *unread.borrow());
+        let id = room.id;
+        let res = cb_sink.send(Box::new(move |siv| {
+            siv.call_on_name("rooms", |rooms: &mut SelectView<RoomSpec>| {
+                let i = rooms.iter().position(|(_, room)| room.id == id);
+                if let Some(i) = i {
+                    if let Some((item, _)) = rooms.get_item_mut(i) {
+                        *item = label;
+                    }
+                }
+            });
+        }));
+        if res.is_err() || unread.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Notifies the user of every message that pings them in a room.
+async fn watch_pings(room: String, mut pings: broadcast::Receiver<Message>, notifier: Notifier, cb_sink: CbSink) {
+    loop {
+        let message = match pings.recv().await {
+            Ok(message) => message,
+            // a burst of pings only needs one notification anyway
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let title = room.clone();
+        let body = format!("{}: {}", message.username, message.text());
+        if cb_sink.send(Box::new(move |_| notifier.notify(&title, &body))).is_err() {
+            return;
+        }
+    }
+}
+
+/// Fetches who's in a room into the user list, and then keeps it up to date as they come and go.
+async fn watch_users(room: Room, cb_sink: CbSink) {
+    let mut users = room.subscribe_users();
+    if let Err(error) = room.users().await {
+        let text = format!("Couldn't load users: {}", error);
+        // the list is still updated with whoever shows up from now on
+        let _ = cb_sink.send(Box::new(move |siv| {
+            siv.call_on_name("user_list", |list: &mut TextView| list.set_content(text));
+        }));
+    }
+    loop {
+        let content = user_list(&users.borrow_and_update());
+        let res = cb_sink.send(Box::new(move |siv| {
+            siv.call_on_name("user_list", |list: &mut TextView| list.set_content(content));
+        }));
+        if res.is_err() || users.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+fn user_list(users: &[RoomUser]) -> StyledString {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut list = StyledString::new();
+    for user in users {
+        if !list.is_empty() {
+            list.append_plain("\n\n");
+        }
+        list.append_styled(&user.username, Effect::Bold);
+        if user.moderator {
+            list.append_plain(" \u{2666}");
+        }
+        if user.owner {
+            list.append_plain(" (owner)");
+        }
+        let mut details = Vec::new();
+        if let Some(reputation) = user.reputation {
+            details.push(format!("{} rep", reputation));
+        }
+        if let Some(last_seen) = user.last_seen {
+            details.push(seen_ago(now.saturating_sub(last_seen)));
+        }
+        if !details.is_empty() {
+            list.append_plain(format!("\n{}", details.join(", ")));
+        }
+    }
+    list
+}
+
+/// Describes how long ago something happened, to the largest whole unit.
+fn seen_ago(ago: Duration) -> String {
+    match ago.as_secs() {
+        0..=59 => String::from("just now"),
+        secs @ 60..=3599 => format!("{}m ago", secs / 60),
+        secs @ 3600..=86399 => format!("{}h ago", secs / 3600),
+        secs => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn room_label(name: &str, unread: Unread) -> StyledString {
+    let mut label = StyledString::plain(name);
+    if unread.messages > 0 {
+        label.append_plain(format!(" ({})", unread.messages));
+    }
+    if unread.mentions > 0 {
+        label.append_styled(format!(" @{}", unread.mentions), Effect::Bold);
+    }
+    label
+}
+
+/// Shows how many of our messages are waiting to be sent.
+async fn watch_outbox(mut queued: watch::Receiver<usize>, cb_sink: CbSink) {
+    loop {
+        let text = match *queued.borrow() {
+            0 => String::new(),
+            1 => String::from("1 message queued"),
+            count => format!("{} messages queued", count),
+        };
+        let res = cb_sink.send(Box::new(move |siv| {
+            siv.call_on_name("outbox", |outbox: &mut TextView| outbox.set_content(text));
+        }));
+        if res.is_err() || queued.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+fn message_view(message: Message, own_id: u64, clock: Clock, quotes: &HashMap<u64, String>) -> MessageView {
+    let quote = message.parent_id.and_then(|id| quotes.get(&id)).map(String::as_str);
+    let own = message.user_id == own_id;
+    MessageView::new(message, own, quote, clock)
+        .on_submit(message_menu)
+        .on_char('r', |siv, message, _| message_key(siv, message, MessageAction::Reply))
+        .on_char('d', |siv, message, own| if own {
+            message_key(siv, message, MessageAction::Delete)
+        })
+}
+
+/// Runs an action on a message straight from a key, if the message menu would offer it.
+fn message_key(siv: &mut Cursive, message: &Message, action: MessageAction) {
+    if message.state != MessageState::Sent || message.deleted {
+        return;
+    }
+    if let Some(context) = siv.user_data::<RoomContext>().cloned() {
+        message_action(siv, &context, message, action);
+    }
+}
+
+/// How many older messages are loaded at a time when scrolling back.
+const SCROLLBACK_PAGE: usize = 50;
+
+/// The length reply quotes are truncated to.
+const QUOTE_LENGTH: usize = 60;
+
+/// Builds a one line quote of the parent of every reply in `messages` whose parent is loaded or was
+/// fetched before, keyed by parent id.
+async fn known_quotes(room: &Room, messages: &[Message]) -> HashMap<u64, String> {
+    let mut quotes = HashMap::new();
+    for parent_id in messages.iter().filter_map(|message| message.parent_id) {
+        if quotes.contains_key(&parent_id) {
+            continue;
+        }
+        let quote = match room.get_message(parent_id).await {
+            Some(parent) => Some(quote(&parent)),
+            None => room.known_message_text(parent_id).await.map(|text| truncate_quote(&text)),
+        };
+        if let Some(quote) = quote {
+            quotes.insert(parent_id, quote);
+        }
+    }
+    quotes
+}
+
+/// Fetches the parents of replies that aren't in `quotes` one at a time, and quotes them in the
+/// message list as they arrive. Returns false if the UI is gone.
+async fn fetch_quotes(room: &Room, messages: &[Message], quotes: &HashMap<u64, String>, cb_sink: &CbSink) -> bool {
+    let mut fetched = Vec::new();
+    for parent_id in messages.iter().filter_map(|message| message.parent_id) {
+        if quotes.contains_key(&parent_id) || fetched.contains(&parent_id) {
+            continue;
+        }
+        fetched.push(parent_id);
+        // without its parent the reply is just shown unquoted
+        let quote = match room.get_message_text(parent_id).await {
+            Ok(text) => truncate_quote(&text),
+            Err(_) => continue,
+        };
+        let res = cb_sink.send(Box::new(move |siv| {
+            siv.call_on_name("messages", |msgs: &mut MessageList| msgs.set_quote(parent_id, &quote));
+        }));
+        if res.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+fn quote(message: &Message) -> String {
+    truncate_quote(&format!("{}: {}", message.username, message.text()))
+}
+
+fn truncate_quote(text: &str) -> String {
+    let text = text.replace('\n', " ");
+    if text.chars().count() > QUOTE_LENGTH {
+        text.chars().take(QUOTE_LENGTH).collect::<String>() + "..."
+    } else {
+        text
+    }
+}
+
 type CbSink = cursive::reexports::crossbeam_channel::Sender<Box<dyn FnOnce(&mut Cursive) + Send + 'static>>;
 
 #[derive(Debug)]
@@ -234,8 +682,54 @@ enum Command {
     Login { email: String, password: String },
     Error(Box<dyn Error + Send>),
     Success,
-    Send(String),
-    Join(u64),
+    /// Sends a message. This has no reply, since the message shows its own progress.
+    Send(String, LongMessage),
+    Edit(u64, String),
+    Delete(u64),
+    /// Like [`Command::Send`], this has no reply.
+    Reply(u64, String, LongMessage),
+    /// Sends a message that failed to send again. This has no reply.
+    Retry(u64),
+    /// Throws away a message that failed to send. This has no reply.
+    Discard(u64),
+    GetReplyTarget,
+    /// The id of the latest message someone else sent and a quote of it, in reply to [`Command::GetReplyTarget`].
+    ReplyTarget(Option<(u64, String)>),
+    ToggleStar(u64),
+    SetPinned(u64, bool),
+    GetSource(u64),
+    /// The markdown source of a message, in reply to [`Command::GetSource`].
+    Source(String),
+    /// Copies the text to the clipboard.
+    Copy(String),
+    /// Loads older messages before the given one. This has no reply.
+    LoadBefore(u64),
+    GetStarboard,
+    Starboard(Vec<StarredMessage>),
+    GetLastOwnMessage,
+    /// The id of our last message if it can still be edited, in reply to [`Command::GetLastOwnMessage`].
+    /// This only looks at the loaded messages, so it doesn't wait on the network.
+    LastOwnMessage(Option<u64>),
+    GetUsernames,
+    /// The names of the users recently active in the current room, in reply to [`Command::GetUsernames`].
+    Usernames(Vec<String>),
+    /// Joins a room if it isn't joined yet, and switches to it. This has no reply.
+    Join(RoomSpec),
+    Leave(u64),
+    /// Closes the session, leaving every room first if `leave_rooms` is set, and then quits.
+    Quit { leave_rooms: bool },
+}
+
+impl Command {
+    /// Whether the UI waits for a reply to the command.
+    fn has_reply(&self) -> bool {
+        matches!(
+            self,
+            Command::Edit(..) | Command::Delete(_) | Command::GetReplyTarget | Command::ToggleStar(_)
+                | Command::SetPinned(..) | Command::GetSource(_) | Command::GetStarboard | Command::GetLastOwnMessage
+                | Command::GetUsernames | Command::Leave(_)
+        )
+    }
 }
 
 fn room_list(siv: &mut Cursive, app: AppRef, from_event: Arc<Mutex<Receiver<Command>>>, to_event: Sender<Command>) {
@@ -256,57 +750,726 @@ fn room_list(siv: &mut Cursive, app: AppRef, from_event: Arc<Mutex<Receiver<Comm
         ),
     );
     siv.add_layer(
-        Dialog::around(view)
-            .title("Room List")
+        OnEventView::new(
+            Dialog::around(view)
+                .title("Room List")
+        )
+            .on_event(Event::CtrlChar('o'), |siv| {
+                // there's nothing to go back to before a room is joined, or after the last one is left
+                let joined = siv.call_on_name("rooms", |rooms: &mut SelectView<RoomSpec>| !rooms.is_empty());
+                if joined.unwrap_or(false) {
+                    swap_room_list(siv);
+                }
+            })
     );
 }
 
 fn in_room(siv: &mut Cursive, app: AppRef, from_event: Arc<Mutex<Receiver<Command>>>, to_event: Sender<Command>) {
-    let moved_to_event = to_event.clone();
-    siv.add_layer(
-        LinearLayout::vertical()
-            .child(
-                ScrollView::new(
-                    LinearLayout::vertical()
-                        .child(DummyView)
-                        .with_name("messages")
-                ).scroll_strategy(ScrollStrategy::StickToBottom)
-            )
-            .child(DummyView)
-            .child(
-                LinearLayout::horizontal()
-                    .child(
-                        TextArea::new()
-                            .with_name("message")
-                            .min_height(1)
-                            .min_width(16)
-                    )
-                    .child(
-                        Button::new("Send", move |siv| {
-                            let message = siv.call_on_name(
-                                "message",
-                                |view: &mut TextArea| view.get_content().to_string(),
-                            ).unwrap();
-                            if message.is_empty() {
-                                return;
+    let compose = Arc::new(Mutex::new(Compose::New));
+    let edit_to_event = to_event.clone();
+    let edit_from_event = from_event.clone();
+    let edit_compose = compose.clone();
+    let del_to_event = to_event.clone();
+    let del_from_event = from_event.clone();
+    let reply_to_event = to_event.clone();
+    let reply_from_event = from_event.clone();
+    let reply_compose = compose.clone();
+    let older_to_event = to_event.clone();
+    let starboard_to_event = to_event.clone();
+    let starboard_from_event = from_event.clone();
+    siv.set_user_data(RoomContext {
+        from_event: from_event.clone(),
+        to_event: to_event.clone(),
+        compose: compose.clone(),
+        completion: Arc::new(Mutex::new(None)),
+    });
+    let room = LinearLayout::vertical()
+        .child(TextView::new("").with_name("status"))
+        .child(
+            MessageList::new()
+                .on_top(move |_, oldest| older_to_event.blocking_send(Command::LoadBefore(oldest)).unwrap())
+                .with_name("messages")
+                .full_height()
+        )
+        .child(DummyView)
+        .child(
+            LinearLayout::horizontal()
+                .child(TextView::new("").with_name("compose_mode").full_width())
+                .child(TextView::new("").with_name("outbox"))
+        )
+        .child(
+            HideableView::new(Panel::new(TextView::new("").with_name("preview_content")).title("Preview"))
+                .hidden()
+                .with_name("preview")
+        )
+        .child(
+            LinearLayout::horizontal()
+                .child(
+                    // every edit goes through here first, so the preview can follow it
+                    OnEventView::new(OnEventView::new(TextArea::new().with_name("message"))
+                        .on_pre_event_inner(Key::Up, move |message, _| {
+                            if !message.get_mut().get_content().is_empty() {
+                                return None;
                             }
-                            moved_to_event.blocking_send(Command::Send(message)).unwrap();
-                            match from_event.lock().unwrap().blocking_recv().unwrap() {
-                                Command::Error(err) => {
-                                    siv.add_layer(Dialog::info(err.to_string()));
-                                }
-                                Command::Success => {
-                                    siv.call_on_name(
-                                        "message",
-                                        |view: &mut TextArea| view.set_content(""),
-                                    ).unwrap();
-                                    siv.focus_name("message").unwrap();
+                            // without a message to edit, Up moves on to the messages above instead
+                            let (id, source) = match last_own_message(&edit_from_event, &edit_to_event) {
+                                Ok(last) => last?,
+                                Err(err) => {
+                                    let err = err.to_string();
+                                    return Some(EventResult::with_cb(move |siv| siv.add_layer(Dialog::info(err.clone()))));
                                 }
-                                _ => {}
+                            };
+                            message.get_mut().set_content(source);
+                            let compose = edit_compose.clone();
+                            Some(EventResult::with_cb(move |siv| {
+                                update_preview(siv);
+                                set_compose(siv, &compose, Compose::Edit(id));
+                            }))
+                        })
+                        .on_pre_event_inner(Key::Del, move |message, _| {
+                            if !message.get_mut().get_content().is_empty() {
+                                return None;
                             }
+                            let to_event = del_to_event.clone();
+                            let from_event = del_from_event.clone();
+                            Some(EventResult::with_cb(move |siv| {
+                                delete_last_message(siv, &from_event, &to_event);
+                            }))
+                        })
+                        .on_pre_event(Event::CtrlChar('r'), move |siv| {
+                            reply_to_last_message(siv, &reply_from_event, &reply_to_event, &reply_compose);
+                        })
+                        .on_pre_event_inner(Event::CtrlChar('k'), |message, _| {
+                            let mut message = message.get_mut();
+                            let content = markdown::toggle_fixed_font(message.get_content());
+                            message.set_content(content);
+                            Some(EventResult::Consumed(None))
+                        })
+                        .on_pre_event_inner(Key::Tab, |message, _| {
+                            let message = message.get_mut();
+                            // without a ping to complete, Tab moves the focus as usual
+                            ping_before(message.get_content(), message.cursor())?;
+                            Some(EventResult::with_cb(complete_ping))
                         })
-                    )
+                        .on_pre_event(Event::AltChar('p'), toggle_preview))
+                        .on_pre_event_inner(EventTrigger::any(), |composer, event| {
+                            let result = View::on_event(composer, event.clone());
+                            // ignored events still have to reach the room's shortcuts
+                            Some(if result.is_consumed() { result.and(EventResult::with_cb(update_preview)) } else { result })
+                        })
+                        .min_height(1)
+                        .min_width(16)
+                )
+                .child(
+                    Button::new("Send", |siv| send_composed(siv, None)).with_name("send")
+                )
+        );
+    let mut room_view = OnEventView::new(
+        LinearLayout::horizontal()
+            .child(
+                Panel::new(
+                    SelectView::<RoomSpec>::new()
+                        .on_submit(|siv, room: &RoomSpec| switch_room(siv, room.clone()))
+                        .with_name("rooms")
+                )
+                    .title("Rooms")
+                    .fixed_width(24)
             )
-    );
+            .child(room.full_width())
+            .child(
+                HideableView::new(
+                    Panel::new(ScrollView::new(LinearLayout::vertical().with_name("starboard_list")))
+                        .title("Starboard")
+                        .fixed_width(40)
+                )
+                    .hidden()
+                    .with_name("starboard")
+            )
+            .child(
+                HideableView::new(
+                    Panel::new(ScrollView::new(TextView::new("Loading...").with_name("user_list")))
+                        .title("Users")
+                        .fixed_width(30)
+                )
+                    .hidden()
+                    .with_name("users")
+            )
+    )
+        .on_event(Event::CtrlChar('s'), move |siv| {
+            toggle_starboard(siv, &starboard_from_event, &starboard_to_event);
+        })
+        .on_event(Event::CtrlChar('u'), toggle_users)
+        .on_event(Event::CtrlChar('n'), |siv| cycle_room(siv, 1))
+        .on_event(Event::CtrlChar('p'), |siv| cycle_room(siv, -1))
+        .on_event(Event::CtrlChar('o'), swap_room_list);
+    for (i, key) in ('1'..='9').enumerate() {
+        room_view.set_on_event(Event::AltChar(key), move |siv| {
+            let room = siv.call_on_name("rooms", |rooms: &mut SelectView<RoomSpec>| {
+                rooms.get_item(i).map(|(_, room)| room.clone())
+            }).flatten();
+            if let Some(room) = room {
+                switch_room(siv, room);
+            }
+        });
+    }
+    siv.add_layer(room_view);
     to_event.blocking_send(Command::Success).unwrap();
 }
+
+/// Takes a room that couldn't be joined back out of the sidebar, and goes back to the room that
+/// was shown before, or to the room list if there's none.
+fn join_failed(siv: &mut Cursive, room_id: u64, current: Option<u64>, error: &str) {
+    let remaining = siv.call_on_name("rooms", |rooms: &mut SelectView<RoomSpec>| {
+        let i = rooms.iter().position(|(_, room)| room.id == room_id);
+        if let Some(i) = i {
+            rooms.remove_item(i);
+        }
+        let i = rooms.iter().position(|(_, room)| Some(room.id) == current);
+        if let Some(i) = i {
+            rooms.set_selection(i);
+        }
+        rooms.len()
+    });
+    if remaining == Some(0) {
+        swap_room_list(siv);
+    }
+    siv.add_layer(Dialog::info(format!("Couldn't join the room: {}", error)));
+}
+
+/// Makes a joined room the one shown, keeping what's typed in the message input.
+fn switch_room(siv: &mut Cursive, room: RoomSpec) {
+    let RoomContext { to_event, compose, .. } = match siv.user_data::<RoomContext>() {
+        Some(context) => context.clone(),
+        None => return,
+    };
+    siv.call_on_name("rooms", |rooms: &mut SelectView<RoomSpec>| {
+        let i = rooms.iter().position(|(_, joined)| joined.id == room.id);
+        if let Some(i) = i {
+            rooms.set_selection(i);
+        }
+    });
+    // the starboard and any reply or edit belong to the room being left
+    siv.call_on_name("starboard", |starboard: &mut Starboard| starboard.hide());
+    set_compose(siv, &compose, Compose::New);
+    to_event.blocking_send(Command::Join(room)).unwrap();
+}
+
+/// Switches to the room `step` places after the current one in the sidebar, wrapping around.
+fn cycle_room(siv: &mut Cursive, step: isize) {
+    let room = siv.call_on_name("rooms", |rooms: &mut SelectView<RoomSpec>| {
+        let current = rooms.selected_id()? as isize;
+        let i = (current + step).rem_euclid(rooms.len() as isize) as usize;
+        rooms.get_item(i).map(|(_, room)| room.clone())
+    }).flatten();
+    if let Some(room) = room {
+        switch_room(siv, room);
+    }
+}
+
+/// Leaves the room being shown, and switches to the next one in the sidebar, or to the room list
+/// if it was the last one.
+fn leave_current_room(siv: &mut Cursive, from_event: &Arc<Mutex<Receiver<Command>>>, to_event: &Sender<Command>) {
+    let current = siv.call_on_name("rooms", |rooms: &mut SelectView<RoomSpec>| {
+        let i = rooms.selected_id()?;
+        rooms.get_item(i).map(|(_, room)| (i, room.id))
+    }).flatten();
+    let (i, room_id) = match current {
+        Some(current) => current,
+        None => return,
+    };
+    to_event.blocking_send(Command::Leave(room_id)).unwrap();
+    // the room is forgotten even if telling the server failed, so carry on regardless
+    if let Command::Error(err) = from_event.lock().unwrap().blocking_recv().unwrap() {
+        siv.add_layer(Dialog::info(format!("Couldn't leave the room: {}", err)));
+    }
+    let next = siv.call_on_name("rooms", |rooms: &mut SelectView<RoomSpec>| {
+        rooms.remove_item(i);
+        rooms.get_item(i.min(rooms.len().saturating_sub(1))).map(|(_, room)| room.clone())
+    }).flatten();
+    match next {
+        Some(room) => switch_room(siv, room),
+        None => {
+            siv.call_on_name("messages", |messages: &mut MessageList| messages.clear());
+            siv.call_on_name("status", |status: &mut TextView| status.set_content(""));
+            siv.call_on_name("outbox", |outbox: &mut TextView| outbox.set_content(""));
+            swap_room_list(siv);
+        }
+    }
+}
+
+/// Asks whether to leave every room before quitting. Nothing needs closing before a room is joined,
+/// so then this quits straight away.
+fn confirm_quit(siv: &mut Cursive) {
+    if siv.find_name::<Dialog>("quit").is_some() {
+        return;
+    }
+    let to_event = match siv.user_data::<RoomContext>() {
+        Some(context) => context.to_event.clone(),
+        None => {
+            siv.quit();
+            return;
+        }
+    };
+    let leave_to_event = to_event.clone();
+    siv.add_layer(
+        Dialog::text("Leave your rooms before quitting? Otherwise you stay in them until the server times you out.")
+            .title("Quit")
+            .button("Leave and quit", move |siv| quit(siv, &leave_to_event, true))
+            .button("Just quit", move |siv| quit(siv, &to_event, false))
+            .dismiss_button("Cancel")
+            .with_name("quit")
+    );
+}
+
+/// Closes the session and then quits, once messages still being sent are out.
+fn quit(siv: &mut Cursive, to_event: &Sender<Command>, leave_rooms: bool) {
+    siv.pop_layer();
+    siv.add_layer(Dialog::text("Closing...").title("Quit").with_name("quit"));
+    to_event.blocking_send(Command::Quit { leave_rooms }).unwrap();
+}
+
+/// Brings the room list to the front to join another room, or goes back to the rooms from it.
+fn swap_room_list(siv: &mut Cursive) {
+    siv.screen_mut().move_to_front(LayerPosition::FromBack(0));
+}
+
+type Preview = HideableView<Panel<NamedView<TextView>>>;
+
+/// Shows how the message input will look once it's sent, or hides it if it's already shown.
+fn toggle_preview(siv: &mut Cursive) {
+    siv.call_on_name("preview", |preview: &mut Preview| preview.set_visible(!preview.is_visible()));
+    update_preview(siv);
+}
+
+/// Renders the message input into the preview, if it's shown.
+fn update_preview(siv: &mut Cursive) {
+    let visible = siv.call_on_name("preview", |preview: &mut Preview| preview.is_visible()).unwrap_or(false);
+    if !visible {
+        return;
+    }
+    let html = siv.call_on_name("message", |view: &mut TextArea| markdown::to_html(view.get_content())).unwrap();
+    siv.call_on_name("preview_content", |content: &mut TextView| content.set_content(render(&html)));
+}
+
+type Starboard = HideableView<ResizedView<Panel<ScrollView<NamedView<LinearLayout>>>>>;
+
+/// Shows the room's starboard next to the messages, or hides it if it's already shown.
+fn toggle_starboard(siv: &mut Cursive, from_event: &Arc<Mutex<Receiver<Command>>>, to_event: &Sender<Command>) {
+    let visible = siv.call_on_name("starboard", |starboard: &mut Starboard| starboard.is_visible()).unwrap();
+    if visible {
+        siv.call_on_name("starboard", |starboard: &mut Starboard| starboard.hide());
+        return;
+    }
+    to_event.blocking_send(Command::GetStarboard).unwrap();
+    match from_event.lock().unwrap().blocking_recv().unwrap() {
+        Command::Starboard(starred) => {
+            siv.call_on_name("starboard_list", |list: &mut LinearLayout| {
+                list.clear();
+                for message in starred {
+                    let pin = if message.pinned { " (pinned)" } else { "" };
+                    list.add_child(TextView::new(
+                        format!("★{}{} {}: {}", message.stars, pin, message.username, message.text)
+                    ));
+                    list.add_child(DummyView);
+                }
+            });
+            siv.call_on_name("starboard", |starboard: &mut Starboard| starboard.unhide());
+        }
+        Command::Error(err) => {
+            siv.add_layer(Dialog::info(err.to_string()));
+        }
+        _ => {}
+    }
+}
+
+type Users = HideableView<ResizedView<Panel<ScrollView<NamedView<TextView>>>>>;
+
+/// Shows who's in the room next to the messages, or hides them if they're already shown.
+fn toggle_users(siv: &mut Cursive) {
+    siv.call_on_name("users", |users: &mut Users| users.set_visible(!users.is_visible()));
+}
+
+/// What sending the contents of the message input does.
+#[derive(Debug, Clone)]
+enum Compose {
+    New,
+    Edit(u64),
+    /// The id of the message being replied to, and a quote of it.
+    Reply(u64, String),
+}
+
+/// Sends the message in the input, as a new message, edit or reply depending on the compose mode.
+/// `long` is what to do if it's too long, which the user is asked if it's `None`.
+fn send_composed(siv: &mut Cursive, long: Option<LongMessage>) {
+    let RoomContext { from_event, to_event, compose, .. } = match siv.user_data::<RoomContext>() {
+        Some(context) => context.clone(),
+        None => return,
+    };
+    let message = siv.call_on_name(
+        "message",
+        |view: &mut TextArea| view.get_content().to_string(),
+    ).unwrap();
+    let mode = compose.lock().unwrap().clone();
+    if message.trim() == "/leave" {
+        siv.call_on_name("message", |view: &mut TextArea| view.set_content(""));
+        update_preview(siv);
+        set_compose(siv, &compose, Compose::New);
+        leave_current_room(siv, &from_event, &to_event);
+        return;
+    }
+    if message.is_empty() {
+        // sending nothing is how an edit or reply is cancelled
+        set_compose(siv, &compose, Compose::New);
+        return;
+    }
+    let editing = matches!(mode, Compose::Edit(_));
+    let limit = match mode {
+        Compose::Reply(id, _) => MESSAGE_LIMIT - reply_prefix(id).len(),
+        _ => MESSAGE_LIMIT,
+    };
+    let long = match long {
+        Some(long) => long,
+        None if !editing && is_too_long(&message, limit) => {
+            let dialog = Dialog::text(format!(
+                "This message is longer than {} characters. How should it be sent?",
+                limit,
+            ))
+                .title("Long message")
+                .button("Fixed font", |siv| {
+                    siv.pop_layer();
+                    send_composed(siv, Some(LongMessage::FixedFont));
+                })
+                .button("Split", |siv| {
+                    siv.pop_layer();
+                    send_composed(siv, Some(LongMessage::Split));
+                });
+            siv.add_layer(dialog.dismiss_button("Cancel"));
+            return;
+        }
+        None => LongMessage::default(),
+    };
+    let command = match mode {
+        Compose::New => Command::Send(message, long),
+        Compose::Edit(id) => Command::Edit(id, message),
+        Compose::Reply(id, _) => Command::Reply(id, message, long),
+    };
+    to_event.blocking_send(command).unwrap();
+    // new messages show up as pending straight away, so only edits wait for the server
+    if editing {
+        if let Command::Error(err) = from_event.lock().unwrap().blocking_recv().unwrap() {
+            siv.add_layer(Dialog::info(err.to_string()));
+            return;
+        }
+    }
+    siv.call_on_name(
+        "message",
+        |view: &mut TextArea| view.set_content(""),
+    ).unwrap();
+    update_preview(siv);
+    set_compose(siv, &compose, Compose::New);
+    siv.focus_name("message").unwrap();
+}
+
+/// Completes the ping before the cursor to the name of someone recently active in the room. If
+/// it was just completed, the next name it could be is used instead.
+fn complete_ping(siv: &mut Cursive) {
+    let RoomContext { from_event, to_event, completion, .. } = match siv.user_data::<RoomContext>() {
+        Some(context) => context.clone(),
+        None => return,
+    };
+    let (content, cursor) = siv.call_on_name(
+        "message",
+        |view: &mut TextArea| (view.get_content().to_string(), view.cursor()),
+    ).unwrap();
+    let (start, typed) = match ping_before(&content, cursor) {
+        Some(ping) => ping,
+        None => return,
+    };
+    let mut completion = completion.lock().unwrap();
+    let name = match completion.as_mut() {
+        Some(completion) if completion.start == start && completion.names[completion.index] == typed => {
+            completion.index = (completion.index + 1) % completion.names.len();
+            completion.names[completion.index].clone()
+        }
+        _ => {
+            to_event.blocking_send(Command::GetUsernames).unwrap();
+            let usernames = match from_event.lock().unwrap().blocking_recv().unwrap() {
+                Command::Usernames(usernames) => usernames,
+                _ => return,
+            };
+            let names = ping_completions(typed, &usernames);
+            let name = match names.first() {
+                Some(name) => name.clone(),
+                None => return,
+            };
+            *completion = Some(PingCompletion { start, names, index: 0 });
+            name
+        }
+    };
+    drop(completion);
+    siv.call_on_name("message", |view: &mut TextArea| {
+        view.set_content(format!("{}{}{}", &content[..start], name, &content[cursor..]));
+        view.set_cursor(start + name.len());
+    });
+    update_preview(siv);
+}
+
+/// The state shared by the callbacks of the room view, kept as the [`Cursive`] user data.
+#[derive(Clone)]
+struct RoomContext {
+    from_event: Arc<Mutex<Receiver<Command>>>,
+    to_event: Sender<Command>,
+    compose: Arc<Mutex<Compose>>,
+    completion: Arc<Mutex<Option<PingCompletion>>>,
+}
+
+/// The names the ping before the cursor can be completed to, which Tab cycles through.
+struct PingCompletion {
+    /// Where the name after the `@` starts in the message input.
+    start: usize,
+    names: Vec<String>,
+    /// The name the ping was last completed to.
+    index: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MessageAction {
+    Reply,
+    Edit,
+    Delete,
+    Star,
+    Pin,
+    Unpin,
+    CopyText,
+    CopyPermalink,
+    OpenProfile,
+    Retry,
+    Discard,
+}
+
+/// Shows the actions that can be taken on a selected message.
+fn message_menu(siv: &mut Cursive, message: &Message, own: bool) {
+    let context = match siv.user_data::<RoomContext>() {
+        Some(context) => context.clone(),
+        None => return,
+    };
+    let mut actions = SelectView::new();
+    match message.state {
+        MessageState::Sent => {}
+        // the server doesn't have it yet, so there's nothing to act on
+        MessageState::Pending => return,
+        MessageState::Failed(_) => {
+            actions.add_item("Retry", MessageAction::Retry);
+            actions.add_item("Discard", MessageAction::Discard);
+            actions.add_item("Copy text", MessageAction::CopyText);
+            show_message_menu(siv, context, message, actions, String::from("Message not sent"));
+            return;
+        }
+    }
+    if !message.deleted {
+        actions.add_item("Reply", MessageAction::Reply);
+        if own {
+            actions.add_item("Edit", MessageAction::Edit);
+            actions.add_item("Delete", MessageAction::Delete);
+        }
+        actions.add_item("Star", MessageAction::Star);
+        if message.is_pinned() {
+            actions.add_item("Unpin", MessageAction::Unpin);
+        } else {
+            actions.add_item("Pin", MessageAction::Pin);
+        }
+        actions.add_item("Copy text", MessageAction::CopyText);
+    }
+    actions.add_item("Copy permalink", MessageAction::CopyPermalink);
+    actions.add_item("Open user profile", MessageAction::OpenProfile);
+    let title = format!("Message by {}", message.username);
+    show_message_menu(siv, context, message, actions, title);
+}
+
+fn show_message_menu(
+    siv: &mut Cursive,
+    context: RoomContext,
+    message: &Message,
+    actions: SelectView<MessageAction>,
+    title: String,
+) {
+    let message = message.clone();
+    siv.add_layer(
+        Dialog::around(actions.on_submit(move |siv, action| {
+            siv.pop_layer();
+            message_action(siv, &context, &message, *action);
+        }))
+            .title(title)
+            .dismiss_button("Cancel")
+    );
+}
+
+fn message_action(siv: &mut Cursive, context: &RoomContext, message: &Message, action: MessageAction) {
+    let RoomContext { from_event, to_event, compose, .. } = context;
+    let command = match action {
+        MessageAction::Reply => {
+            set_compose(siv, compose, Compose::Reply(message.id, quote(message)));
+            siv.focus_name("message").unwrap();
+            return;
+        }
+        MessageAction::OpenProfile => {
+            let url = format!("https://chat.stackexchange.com/users/{}", message.user_id);
+            if let Err(err) = open_url(&url) {
+                siv.add_layer(Dialog::info(format!("Couldn't open {}: {}", url, err)));
+            }
+            return;
+        }
+        MessageAction::Edit if !message.is_editable() => {
+            siv.add_layer(Dialog::info(SeError::EditWindowExpired.to_string()));
+            return;
+        }
+        MessageAction::Retry | MessageAction::Discard => {
+            let command = if let MessageAction::Retry = action {
+                Command::Retry(message.id)
+            } else {
+                Command::Discard(message.id)
+            };
+            to_event.blocking_send(command).unwrap();
+            return;
+        }
+        MessageAction::Edit | MessageAction::Delete => Command::GetSource(message.id),
+        MessageAction::Star => Command::ToggleStar(message.id),
+        MessageAction::Pin => Command::SetPinned(message.id, true),
+        MessageAction::Unpin => Command::SetPinned(message.id, false),
+        MessageAction::CopyText => Command::Copy(message.text()),
+        MessageAction::CopyPermalink => Command::Copy(format!(
+            "https://chat.stackexchange.com/transcript/message/{0}#{0}",
+            message.id
+        )),
+    };
+    to_event.blocking_send(command).unwrap();
+    match from_event.lock().unwrap().blocking_recv().unwrap() {
+        Command::Source(source) => {
+            if let MessageAction::Edit = action {
+                siv.call_on_name("message", |view: &mut TextArea| view.set_content(source)).unwrap();
+                update_preview(siv);
+                set_compose(siv, compose, Compose::Edit(message.id));
+                siv.focus_name("message").unwrap();
+            } else {
+                confirm_delete(siv, from_event, to_event, message.id, source);
+            }
+        }
+        Command::Error(err) => {
+            siv.add_layer(Dialog::info(err.to_string()));
+        }
+        _ => {}
+    }
+}
+
+/// Opens a URL in the system's web browser.
+fn open_url(url: &str) -> std::io::Result<()> {
+    #[cfg(target_os = "windows")]
+    let mut command = {
+        let mut command = process::Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    };
+    #[cfg(target_os = "macos")]
+    let mut command = process::Command::new("open");
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let mut command = process::Command::new("xdg-open");
+    command.arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map(|_| ())
+}
+
+/// Gets the id and markdown source of our last message, if it can still be edited.
+fn last_own_message(
+    from_event: &Arc<Mutex<Receiver<Command>>>,
+    to_event: &Sender<Command>,
+) -> Result<Option<(u64, String)>, Box<dyn Error + Send>> {
+    to_event.blocking_send(Command::GetLastOwnMessage).unwrap();
+    let id = match from_event.lock().unwrap().blocking_recv().unwrap() {
+        Command::LastOwnMessage(Some(id)) => id,
+        Command::LastOwnMessage(None) => return Ok(None),
+        Command::Error(err) => return Err(err),
+        x => unreachable!("{:?}", x),
+    };
+    // the source is only fetched once there's known to be a message to edit
+    to_event.blocking_send(Command::GetSource(id)).unwrap();
+    match from_event.lock().unwrap().blocking_recv().unwrap() {
+        Command::Source(source) => Ok(Some((id, source))),
+        Command::Error(err) => Err(err),
+        x => unreachable!("{:?}", x),
+    }
+}
+
+/// Makes the message input reply to the latest message someone else sent.
+fn reply_to_last_message(
+    siv: &mut Cursive,
+    from_event: &Arc<Mutex<Receiver<Command>>>,
+    to_event: &Sender<Command>,
+    compose: &Arc<Mutex<Compose>>,
+) {
+    to_event.blocking_send(Command::GetReplyTarget).unwrap();
+    if let Command::ReplyTarget(Some((id, quote))) = from_event.lock().unwrap().blocking_recv().unwrap() {
+        set_compose(siv, compose, Compose::Reply(id, quote));
+    }
+}
+
+/// Asks for confirmation and then deletes our last message.
+fn delete_last_message(siv: &mut Cursive, from_event: &Arc<Mutex<Receiver<Command>>>, to_event: &Sender<Command>) {
+    match last_own_message(from_event, to_event) {
+        Ok(Some((id, source))) => confirm_delete(siv, from_event, to_event, id, source),
+        Ok(None) => {}
+        Err(err) => {
+            siv.add_layer(Dialog::info(err.to_string()));
+        }
+    }
+}
+
+/// Asks for confirmation and then deletes a message, offering to send it again afterwards.
+fn confirm_delete(
+    siv: &mut Cursive,
+    from_event: &Arc<Mutex<Receiver<Command>>>,
+    to_event: &Sender<Command>,
+    id: u64,
+    source: String,
+) {
+    let from_event = from_event.clone();
+    let to_event = to_event.clone();
+    siv.add_layer(
+        Dialog::text(format!("Delete this message?\n\n{}", source))
+            .button("Delete", move |siv| {
+                siv.pop_layer();
+                to_event.blocking_send(Command::Delete(id)).unwrap();
+                match from_event.lock().unwrap().blocking_recv().unwrap() {
+                    Command::Error(err) => {
+                        siv.add_layer(Dialog::info(err.to_string()));
+                    }
+                    Command::Success => {
+                        let to_event = to_event.clone();
+                        let source = source.clone();
+                        siv.add_layer(
+                            Dialog::text("Message deleted")
+                                .button("Undo", move |siv| {
+                                    siv.pop_layer();
+                                    to_event.blocking_send(Command::Send(source.clone(), LongMessage::Unchanged)).unwrap();
+                                })
+                                .dismiss_button("Ok")
+                        );
+                    }
+                    _ => {}
+                }
+            })
+            .dismiss_button("Cancel")
+    );
+}
+
+fn set_compose(siv: &mut Cursive, compose: &Arc<Mutex<Compose>>, mode: Compose) {
+    let (label, description) = match &mode {
+        Compose::New => ("Send", String::new()),
+        Compose::Edit(_) => ("Edit", String::from("Editing message")),
+        Compose::Reply(_, quote) => ("Reply", format!("Replying to {}", quote)),
+    };
+    *compose.lock().unwrap() = mode;
+    siv.call_on_name("send", |button: &mut Button| button.set_label(label));
+    siv.call_on_name("compose_mode", |view: &mut TextView| view.set_content(description));
+}
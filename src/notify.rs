@@ -0,0 +1,54 @@
+use std::env;
+use std::io::{self, Write};
+
+/// How to alert the user when a message pings them.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Notifier {
+    #[default]
+    Off,
+    /// Rings the terminal bell.
+    Bell,
+    /// Shows a desktop notification with the OSC 9 escape sequence, which iTerm2, Windows Terminal
+    /// and kitty understand.
+    Osc9,
+    /// Shows a desktop notification with the OSC 777 escape sequence, which VTE based terminals
+    /// like GNOME Terminal understand.
+    Osc777,
+}
+
+impl Notifier {
+    /// Reads the notifier from the `LIGHTCHAT_NOTIFY` environment variable, which can be `bell`,
+    /// `osc9` or `osc777`. Anything else turns notifications off.
+    pub fn from_env() -> Self {
+        match env::var("LIGHTCHAT_NOTIFY").as_deref() {
+            Ok("bell") => Notifier::Bell,
+            Ok("osc9") => Notifier::Osc9,
+            Ok("osc777") => Notifier::Osc777,
+            _ => Notifier::Off,
+        }
+    }
+
+    /// Writes the escape sequence for a notification to the terminal. This has to run on the UI
+    /// thread, so it doesn't end up in the middle of what's being drawn.
+    pub fn notify(self, title: &str, body: &str) {
+        let sequence = match self {
+            Notifier::Off => return,
+            Notifier::Bell => String::from("\x07"),
+            Notifier::Osc9 => format!("\x1b]9;{}: {}\x07", strip_controls(title), strip_controls(body)),
+            // the fields are separated by semicolons, which the body can have since it's last
+            Notifier::Osc777 => format!(
+                "\x1b]777;notify;{};{}\x07",
+                strip_controls(title).replace(';', ","),
+                strip_controls(body),
+            ),
+        };
+        let mut stdout = io::stdout();
+        // a missed notification isn't worth bothering anyone about
+        let _ = stdout.write_all(sequence.as_bytes()).and_then(|_| stdout.flush());
+    }
+}
+
+/// Removes control characters, which could end the escape sequence early.
+fn strip_controls(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control()).collect()
+}
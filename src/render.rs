@@ -0,0 +1,288 @@
+use cursive::theme::{Effect, PaletteColor, Style};
+use cursive::utils::markup::StyledString;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::Name;
+
+/// Where relative links in messages, like those of tags and users, point to.
+const SITE: &str = "https://chat.stackexchange.com";
+
+/// How much of a onebox's text is shown when it has no title.
+const ONEBOX_SUMMARY_LENGTH: usize = 100;
+
+/// Renders the HTML of a chat message as styled text.
+///
+/// This knows about the HTML the chat server produces: inline formatting, links, tags, fixed font
+/// `<pre>` blocks, multi-line messages, quotes and oneboxes, which are summarized as a line with
+/// what they are, their title and their link.
+pub fn render(html: &str) -> StyledString {
+    let document = Document::from(html);
+    let mut renderer = Renderer::new();
+    if let Some(body) = document.find(Name("body")).next() {
+        renderer.children(&body);
+    }
+    renderer.out
+}
+
+struct Renderer {
+    out: StyledString,
+    /// The styles of the elements being rendered, which all apply to their text.
+    styles: Vec<Style>,
+    /// How many quotes deep the text being rendered is.
+    quotes: usize,
+    /// Whether nothing has been written on the current line yet, not even the quote prefix.
+    line_start: bool,
+    /// Line breaks not written yet, so that none are left at the end.
+    newlines: usize,
+    /// Whitespace isn't collapsed inside `<pre>` elements.
+    pre: bool,
+}
+
+impl Renderer {
+    fn new() -> Self {
+        Self { out: StyledString::new(), styles: Vec::new(), quotes: 0, line_start: true, newlines: 0, pre: false }
+    }
+
+    fn children(&mut self, node: &Node) {
+        for child in node.children() {
+            self.node(&child);
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        if let Some(text) = node.as_text() {
+            self.text(text);
+            return;
+        }
+        let name = match node.name() {
+            Some(name) => name,
+            None => return,
+        };
+        match name {
+            "br" => self.newline(),
+            "b" | "strong" => self.styled(node, Effect::Bold),
+            "i" | "em" => self.styled(node, Effect::Italic),
+            "strike" | "del" | "s" => self.styled(node, Effect::Strikethrough),
+            "code" => self.styled(node, PaletteColor::Secondary),
+            "img" => {
+                let src = node.attr("src").unwrap_or_default();
+                self.push(&format!("[image: {}]", absolute(src)), Effect::Underline.into());
+            }
+            "a" => self.link(node),
+            "span" if has_class(node, "ob-post-tag") => self.tag(node),
+            "pre" => {
+                self.block();
+                let pre = std::mem::replace(&mut self.pre, true);
+                self.styled(node, PaletteColor::Secondary);
+                self.pre = pre;
+                self.block();
+            }
+            "blockquote" => self.quote(node),
+            "div" if has_class(node, "quote") => self.quote(node),
+            "div" if has_class(node, "onebox") => self.onebox(node),
+            "div" | "p" | "ul" | "ol" | "li" => {
+                self.block();
+                self.children(node);
+                self.block();
+            }
+            _ => self.children(node),
+        }
+    }
+
+    fn styled(&mut self, node: &Node, style: impl Into<Style>) {
+        self.styles.push(style.into());
+        self.children(node);
+        self.styles.pop();
+    }
+
+    fn link(&mut self, node: &Node) {
+        // tags are links around a span, which is rendered as a badge
+        if node.children().any(|child| has_class(&child, "ob-post-tag")) {
+            self.children(node);
+            return;
+        }
+        let href = node.attr("href").map(absolute).unwrap_or_default();
+        self.styled(node, Effect::Underline);
+        if !href.is_empty() && collapse(&node.text()).trim() != href {
+            self.push(&format!(" ({})", href), PaletteColor::Secondary.into());
+        }
+    }
+
+    fn tag(&mut self, node: &Node) {
+        self.push(&format!(" {} ", node.text().trim()), Effect::Reverse.into());
+    }
+
+    fn quote(&mut self, node: &Node) {
+        self.block();
+        self.quotes += 1;
+        self.styled(node, Effect::Italic);
+        self.quotes -= 1;
+        self.block();
+    }
+
+    /// Summarizes a onebox as its kind, like `youtube` or `wikipedia`, its title and its link.
+    fn onebox(&mut self, node: &Node) {
+        let kind = node.attr("class")
+            .and_then(|class| class.split_whitespace().find_map(|class| class.strip_prefix("ob-")))
+            .unwrap_or("onebox");
+        let link = node.find(Name("a")).find_map(|a| a.attr("href")).map(absolute);
+        let title = node.descendants()
+            .find(|descendant| {
+                descendant.attr("class").is_some_and(|class| class.split_whitespace().any(|class| class.ends_with("-title")))
+            })
+            .map(|title| collapse(&title.text()).trim().to_string())
+            .unwrap_or_else(|| {
+                let text = collapse(&node.text()).trim().to_string();
+                if text.chars().count() > ONEBOX_SUMMARY_LENGTH {
+                    text.chars().take(ONEBOX_SUMMARY_LENGTH).collect::<String>() + "..."
+                } else {
+                    text
+                }
+            });
+        self.block();
+        self.push(&format!("[{}]", kind), Effect::Bold.into());
+        if !title.is_empty() {
+            self.push(&format!(" {}", title), Style::none());
+        }
+        if let Some(link) = link {
+            self.push(" ", Style::none());
+            self.push(&link, Effect::Underline.into());
+        }
+        self.block();
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.pre {
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    self.newline();
+                }
+                if !line.is_empty() {
+                    self.push(line, Style::none());
+                }
+            }
+            return;
+        }
+        let text = collapse(text);
+        // whitespace at the start of a line is only there because of how the HTML is laid out
+        let text = if self.line_start { text.trim_start() } else { &text };
+        if !text.is_empty() {
+            self.push(text, Style::none());
+        }
+    }
+
+    /// Writes text in the current styles combined with `style`.
+    fn push(&mut self, text: &str, style: Style) {
+        if self.newlines > 0 {
+            self.out.append_plain("\n".repeat(self.newlines));
+            self.newlines = 0;
+        }
+        if self.line_start && self.quotes > 0 {
+            self.out.append_styled("\u{2502} ".repeat(self.quotes), PaletteColor::Secondary);
+        }
+        self.line_start = false;
+        let mut styles = self.styles.clone();
+        styles.push(style);
+        self.out.append_styled(text, Style::merge(&styles));
+    }
+
+    fn newline(&mut self) {
+        self.newlines += 1;
+        self.line_start = true;
+    }
+
+    /// Starts a new line, unless nothing has been written on the current one yet.
+    fn block(&mut self) {
+        if !self.line_start {
+            self.newline();
+        }
+    }
+}
+
+fn has_class(node: &Node, class: &str) -> bool {
+    node.attr("class").is_some_and(|classes| classes.split_whitespace().any(|c| c == class))
+}
+
+/// Replaces every run of whitespace with a single space, like HTML does outside `<pre>`.
+fn collapse(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut space = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !space {
+                collapsed.push(' ');
+            }
+            space = true;
+        } else {
+            collapsed.push(c);
+            space = false;
+        }
+    }
+    collapsed
+}
+
+fn absolute(url: &str) -> String {
+    if url.starts_with("//") {
+        format!("https:{}", url)
+    } else if url.starts_with('/') {
+        format!("{}{}", SITE, url)
+    } else {
+        url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(html: &str) -> String {
+        render(html).source().to_string()
+    }
+
+    #[test]
+    fn collapses_whitespace() {
+        assert_eq!(text("a  \n  b"), "a b");
+        assert_eq!(text("<div class='full'>  a<br>  b</div>"), "a\nb");
+    }
+
+    #[test]
+    fn keeps_whitespace_in_pre() {
+        assert_eq!(text("<pre class='full'>fn a() {\n    b  c\n}</pre>"), "fn a() {\n    b  c\n}");
+    }
+
+    #[test]
+    fn prefixes_quote_lines() {
+        assert_eq!(text("<div class='quote'>a<br>b</div>"), "\u{2502} a\n\u{2502} b");
+        assert_eq!(text("<div class='quote'>a<blockquote>b</blockquote></div>"), "\u{2502} a\n\u{2502} \u{2502} b");
+    }
+
+    #[test]
+    fn shows_tags_as_badges() {
+        let styled = render("<a href=\"/tags/rust/info\"><span class=\"ob-post-tag\">rust</span></a>");
+        assert_eq!(styled.source(), " rust ");
+        let span = styled.spans().next().unwrap();
+        assert_eq!(span.content, " rust ");
+        assert!(span.attr.effects.contains(Effect::Reverse));
+    }
+
+    #[test]
+    fn shows_link_targets_that_differ_from_their_text() {
+        assert_eq!(text("<a href=\"https://example.com\">site</a>"), "site (https://example.com)");
+        assert_eq!(text("<a href=\"https://example.com\">https://example.com</a>"), "https://example.com");
+        assert_eq!(text("<a href=\"/users/1\">me</a>"), "me (https://chat.stackexchange.com/users/1)");
+    }
+
+    #[test]
+    fn summarizes_oneboxes() {
+        let html = "<div class=\"onebox ob-youtube\"><a href=\"https://youtu.be/x\"><img src=\"//i.ytimg.com/x.jpg\"></a>\
+            <div class=\"ob-youtube-title\">  A   video </div></div>";
+        assert_eq!(text(html), "[youtube] A video https://youtu.be/x");
+    }
+
+    #[test]
+    fn leaves_no_trailing_newline() {
+        assert_eq!(text("<p>a</p><p>b</p>"), "a\nb");
+        assert_eq!(text("a<br><br>"), "a");
+        assert_eq!(text("<pre>a\n</pre>"), "a");
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use reqwest::Client;
+use serde_json::Value;
+use tokio::sync::{Mutex, Notify, watch};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use crate::se::{EventHandlers, RoomHandlers, SeError};
+use crate::se::event::{dispatch_events, LastEventIds, on_ws_conn, ws_connect};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting { attempt: u32 },
+    Offline { reason: String },
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Connecting => write!(f, "Connecting..."),
+            ConnectionState::Connected => write!(f, "Connected"),
+            ConnectionState::Reconnecting { attempt } => write!(f, "Reconnecting (attempt {})...", attempt),
+            ConnectionState::Offline { reason } => write!(f, "Offline: {}", reason),
+        }
+    }
+}
+
+/// A single websocket shared by every room the user is in, which fans incoming events out to the
+/// handlers of the room they belong to. If the socket drops it is reopened with exponential
+/// backoff, and any events missed in the meantime are fetched and dispatched before new ones.
+pub struct Connection {
+    inner: Arc<Inner>,
+    task: JoinHandle<()>,
+}
+
+struct Inner {
+    client: Client,
+    fkey: String,
+    rooms: RoomHandlers,
+    rooms_changed: Notify,
+    last_event_ids: LastEventIds,
+    state: watch::Sender<ConnectionState>,
+}
+
+impl Connection {
+    pub fn new(client: Client, fkey: String) -> Self {
+        let inner = Arc::new(Inner {
+            client,
+            fkey,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            rooms_changed: Notify::new(),
+            last_event_ids: Arc::new(Mutex::new(HashMap::new())),
+            state: watch::channel(ConnectionState::Connecting).0,
+        });
+        let moved_inner = inner.clone();
+        let task = tokio::spawn(async move {
+            let inner = moved_inner;
+            let mut attempt = 0;
+            loop {
+                // ws-auth wants a room to authenticate against, but the socket carries all of them
+                let auth_room = inner.rooms.lock().await.keys().next().copied();
+                let auth_room = match auth_room {
+                    Some(room_id) => room_id,
+                    None => {
+                        inner.rooms_changed.notified().await;
+                        continue;
+                    }
+                };
+                if attempt > 0 {
+                    inner.state.send_replace(ConnectionState::Reconnecting { attempt });
+                }
+                let reason = match inner.run(auth_room, &mut attempt).await {
+                    Ok(()) => String::from("Connection closed"),
+                    Err(error) => error.to_string(),
+                };
+                inner.state.send_replace(ConnectionState::Offline { reason });
+                attempt += 1;
+                sleep(backoff(attempt)).await;
+            }
+        });
+        Self { inner, task }
+    }
+
+    /// Starts routing the events of the given room to `handlers`. No reconnection is needed.
+    /// `last_event_id` is the newest event already loaded, and anything after it is caught up on
+    /// in the background, since events aren't routed to the room before it's added.
+    pub async fn add_room(&self, room_id: u64, handlers: EventHandlers, last_event_id: u64) {
+        self.inner.last_event_ids.lock().await.insert(room_id, last_event_id);
+        self.inner.rooms.lock().await.insert(room_id, handlers.clone());
+        self.inner.rooms_changed.notify_one();
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            // if this fails, catching up after the next reconnect gets the events instead
+            let _ = inner.catch_up_room(room_id, &handlers).await;
+        });
+    }
+
+    /// Returns a receiver that is notified whenever the state of the connection changes.
+    pub fn state(&self) -> watch::Receiver<ConnectionState> {
+        self.inner.state.subscribe()
+    }
+
+    pub async fn remove_room(&self, room_id: u64) {
+        self.inner.rooms.lock().await.remove(&room_id);
+        self.inner.last_event_ids.lock().await.remove(&room_id);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Inner {
+    /// Connects, catches up on missed events and then reads events until the socket closes.
+    /// `attempt` is reset once the connection is back up.
+    async fn run(&self, auth_room: u64, attempt: &mut u32) -> Result<(), SeError> {
+        let ws = ws_connect(self.ws_url(auth_room).await?).await?;
+        self.catch_up().await?;
+        *attempt = 0;
+        self.state.send_replace(ConnectionState::Connected);
+        on_ws_conn(ws, self.rooms.clone(), self.last_event_ids.clone()).await
+    }
+
+    async fn ws_url(&self, auth_room: u64) -> Result<String, SeError> {
+        let response = self.client.post("https://chat.stackexchange.com/ws-auth")
+            .form(&[("roomid", auth_room.to_string()), ("fkey", self.fkey.clone())])
+            .send()
+            .await?
+            .json::<Value>()
+            .await?;
+        match &response["url"] {
+            Value::String(url) => Ok(format!(
+                "{}?l={}",
+                url,
+                SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+            )),
+            _ => Err(SeError::BadResponse(200, response.to_string())),
+        }
+    }
+
+    /// Fetches and dispatches the events each room got since the last one we saw there.
+    async fn catch_up(&self) -> Result<(), SeError> {
+        let rooms = self.rooms.lock().await.clone();
+        for (room_id, handlers) in rooms {
+            self.catch_up_room(room_id, &handlers).await?;
+        }
+        Ok(())
+    }
+
+    async fn catch_up_room(&self, room_id: u64, handlers: &EventHandlers) -> Result<(), SeError> {
+        let since = self.last_event_ids.lock().await.get(&room_id).copied().unwrap_or_default();
+        let response = self.client.post(format!("https://chat.stackexchange.com/chats/{}/events", room_id))
+            .form(&[
+                ("since", since.to_string()),
+                ("mode", String::from("Messages")),
+                ("msgCount", String::from("100")),
+                ("fkey", self.fkey.clone()),
+            ])
+            .send()
+            .await?
+            .json::<Value>()
+            .await?;
+        if let Some(events) = response["events"].as_array() {
+            // live events that arrived during the request have advanced the id, and are skipped
+            let mut last_event_ids = self.last_event_ids.lock().await;
+            dispatch_events(events, handlers, last_event_ids.entry(room_id).or_default()).await;
+        }
+        Ok(())
+    }
+}
+
+/// Exponential backoff with up to 50% random jitter, so clients don't all reconnect at once.
+fn backoff(attempt: u32) -> Duration {
+    let delay = MIN_BACKOFF.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(MAX_BACKOFF);
+    // the clock's sub-second part is random enough for jitter without pulling in a crate for it
+    let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().subsec_nanos();
+    delay + delay.mul_f64(f64::from(nanos % 1000) / 2000.0)
+}
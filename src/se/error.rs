@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use thiserror::Error;
 use crate::se::event::ChatEventType;
 
@@ -6,18 +8,43 @@ pub enum SeError {
     #[error("reqwest error: {0}")]
     Reqwest(#[from] reqwest::Error),
 
+    #[error("websocket error: {0}")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+
+    #[error("Nothing received for {} seconds", .0.as_secs())]
+    ReadTimeout(Duration),
+
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+
     #[error("{0}")]
     Login(String),
 
     #[error("Bad credentials")]
     BadCredentials,
 
-    #[error("Rate limited")]
-    RateLimit,
+    #[error("Rate limited, try again in {} seconds", .0.as_secs())]
+    RateLimit(Duration),
+
+    #[error("Messages can only be edited or deleted within 2 minutes of sending them")]
+    EditWindowExpired,
+
+    #[error("Message {0} couldn't be fetched")]
+    MessageUnavailable(u64),
+
+    #[error("Not in a room")]
+    NotInRoom,
 
     #[error("Bad response: {0}: {1}")]
     BadResponse(u16, String),
     
     #[error("Expected message event, got {0:?}")]
-    ExpectedMessageEvent(ChatEventType),
-}
\ No newline at end of file
+    ExpectedMessageEvent(Box<ChatEventType>),
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for SeError {
+    fn from(error: tokio_tungstenite::tungstenite::Error) -> Self {
+        // boxed because it's much larger than every other variant
+        SeError::WebSocket(Box::new(error))
+    }
+}
@@ -1,17 +1,22 @@
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 
 use futures_util::StreamExt;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 use serde_with::serde_as;
-use tokio_tungstenite::connect_async;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::http::header::{ORIGIN, USER_AGENT};
 use tokio_tungstenite::tungstenite::http::HeaderValue;
 use tokio_tungstenite::tungstenite::Message;
 
 use crate::app::APP_USER_AGENT;
-use crate::se::EventHandlers;
+use crate::se::{EventHandlers, RoomHandlers, SeError};
 
 /*
 {"content":"test","event_type":1,"id":141800943,"message_id":63567474,"room_id":1,"room_name":"Sandbox","time_stamp":1684029252,"user_id":526756,"user_name":"Seggan"}
@@ -19,12 +24,14 @@ use crate::se::EventHandlers;
 {"event_type":10,"id":141800967,"message_id":63567485,"room_id":1,"room_name":"Sandbox","time_stamp":1684029470,"user_id":526756,"user_name":"Seggan"}
  */
 
+/// The fields shared by every event that refers to a chat message.
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatEvent {
     pub id: u64,
     pub message_id: u64,
     pub room_id: u64,
+    #[serde(default)]
     pub room_name: String,
     #[serde_as(as = "serde_with::DurationSeconds<u64>")]
     #[serde(rename = "time_stamp")]
@@ -34,55 +41,290 @@ pub struct ChatEvent {
     pub username: String,
 }
 
+/// The fields shared by every event, including those not tied to a message or user.
+#[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(untagged)]
+pub struct RoomEvent {
+    pub id: u64,
+    pub room_id: u64,
+    #[serde(default)]
+    pub room_name: String,
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(rename = "time_stamp")]
+    pub timestamp: Duration,
+}
+
+/// A chat event, keyed on its numeric `event_type`.
+///
+/// Event types we don't know about (or known ones that come through in an unexpected shape) are
+/// kept as [`ChatEventType::Other`] instead of failing to parse.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(remote = "Self", tag = "event_type")]
 pub enum ChatEventType {
+    #[serde(rename = "1")]
+    Message {
+        #[serde(flatten)]
+        event: ChatEvent,
+        content: String,
+        /// Set when the message is a reply, i.e. starts with `:<parent id>`.
+        #[serde(default)]
+        parent_id: Option<u64>,
+        #[serde(default)]
+        show_parent: bool,
+    },
+    #[serde(rename = "2")]
     Edit {
         #[serde(flatten)]
         event: ChatEvent,
         message_edits: u64,
         content: String,
     },
-    Message {
+    #[serde(rename = "3")]
+    UserEntered {
+        #[serde(flatten)]
+        event: RoomEvent,
+        user_id: u64,
+        #[serde(rename = "user_name")]
+        username: String,
+    },
+    #[serde(rename = "4")]
+    UserLeft {
+        #[serde(flatten)]
+        event: RoomEvent,
+        user_id: u64,
+        #[serde(rename = "user_name")]
+        username: String,
+    },
+    /// `content` holds the new room name and description.
+    #[serde(rename = "5")]
+    RoomNameChanged {
+        #[serde(flatten)]
+        event: RoomEvent,
+        user_id: u64,
+        #[serde(rename = "user_name")]
+        username: String,
+        content: String,
+    },
+    /// Sent both for stars and for pins (which the server calls owner stars).
+    #[serde(rename = "6")]
+    MessageStarred {
+        #[serde(flatten)]
+        event: RoomEvent,
+        message_id: u64,
+        #[serde(default)]
+        content: String,
+        #[serde(rename = "message_stars", default)]
+        stars: u64,
+        #[serde(rename = "message_owner_stars", default)]
+        owner_stars: u64,
+        #[serde(rename = "message_starred", default)]
+        starred: bool,
+    },
+    #[serde(rename = "7")]
+    Debug {
+        #[serde(flatten)]
+        event: RoomEvent,
+        #[serde(default)]
+        content: String,
+    },
+    #[serde(rename = "8")]
+    Mention {
         #[serde(flatten)]
         event: ChatEvent,
         content: String,
+        target_user_id: u64,
+        #[serde(default)]
+        parent_id: Option<u64>,
     },
+    #[serde(rename = "9")]
+    Flag {
+        #[serde(flatten)]
+        event: RoomEvent,
+        #[serde(default)]
+        message_id: Option<u64>,
+        #[serde(default)]
+        content: String,
+    },
+    #[serde(rename = "10")]
     Delete {
         #[serde(flatten)]
         event: ChatEvent
     },
+    #[serde(rename = "11")]
+    FileAdded {
+        #[serde(flatten)]
+        event: ChatEvent,
+        content: String,
+    },
+    #[serde(rename = "12")]
+    ModeratorFlag {
+        #[serde(flatten)]
+        event: RoomEvent,
+        #[serde(default)]
+        message_id: Option<u64>,
+        #[serde(default)]
+        content: String,
+    },
+    #[serde(rename = "13")]
+    UserSettingsChanged {
+        #[serde(flatten)]
+        event: RoomEvent,
+        #[serde(default)]
+        content: String,
+    },
+    #[serde(rename = "14")]
+    GlobalNotification {
+        #[serde(flatten)]
+        event: RoomEvent,
+        #[serde(default)]
+        content: String,
+    },
+    /// Also sent when a user is kicked from the room, in which case `content` says so.
+    #[serde(rename = "15")]
+    AccessLevelChanged {
+        #[serde(flatten)]
+        event: RoomEvent,
+        user_id: u64,
+        target_user_id: u64,
+        #[serde(default)]
+        content: String,
+    },
+    #[serde(rename = "16")]
+    UserNotification {
+        #[serde(flatten)]
+        event: RoomEvent,
+        #[serde(default)]
+        target_user_id: Option<u64>,
+        #[serde(default)]
+        content: String,
+    },
+    #[serde(rename = "17")]
+    Invitation {
+        #[serde(flatten)]
+        event: RoomEvent,
+        user_id: u64,
+        #[serde(rename = "user_name")]
+        username: String,
+        #[serde(default)]
+        target_user_id: Option<u64>,
+        #[serde(default)]
+        content: String,
+    },
+    #[serde(rename = "18")]
+    Reply {
+        #[serde(flatten)]
+        event: ChatEvent,
+        content: String,
+        parent_id: u64,
+        target_user_id: u64,
+    },
+    #[serde(rename = "19")]
+    MessageMovedOut {
+        #[serde(flatten)]
+        event: ChatEvent,
+        content: String,
+    },
+    #[serde(rename = "20")]
+    MessageMovedIn {
+        #[serde(flatten)]
+        event: ChatEvent,
+        content: String,
+    },
+    #[serde(rename = "21")]
+    TimeBreak {
+        #[serde(flatten)]
+        event: RoomEvent,
+    },
+    #[serde(rename = "22")]
+    FeedTicker {
+        #[serde(flatten)]
+        event: RoomEvent,
+        #[serde(default)]
+        content: String,
+    },
+    #[serde(rename = "29")]
+    UserSuspended {
+        #[serde(flatten)]
+        event: RoomEvent,
+        #[serde(default)]
+        content: String,
+    },
+    #[serde(rename = "30")]
+    UserMerged {
+        #[serde(flatten)]
+        event: RoomEvent,
+        #[serde(default)]
+        content: String,
+    },
+    #[serde(rename = "34")]
+    UserNameChanged {
+        #[serde(flatten)]
+        event: RoomEvent,
+        user_id: u64,
+        #[serde(rename = "user_name")]
+        username: String,
+    },
+    #[serde(skip)]
+    Other {
+        event_type: u64,
+        raw: Value,
+    },
+}
+
+impl<'de> Deserialize<'de> for ChatEventType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Value::deserialize(deserializer)?;
+        let event_type = raw.get("event_type")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| de::Error::missing_field("event_type"))?;
+        // serde can only match string tags, so retag a copy with the stringified number
+        let mut tagged = raw.clone();
+        tagged["event_type"] = Value::String(event_type.to_string());
+        Ok(ChatEventType::deserialize(&tagged).unwrap_or(ChatEventType::Other { event_type, raw }))
+    }
 }
 
-pub async fn on_ws_conn(url: String, room_id: u64, event_handlers: EventHandlers) -> Result<(), Box<dyn std::error::Error>> {
-    let room_key = format!("r{}", room_id);
+pub type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How long the socket can go without receiving anything before it's assumed to have dropped
+/// without closing, like after the computer slept.
+const READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The id of the newest event seen in each room, keyed by room id.
+pub type LastEventIds = Arc<Mutex<HashMap<u64, u64>>>;
+
+pub async fn ws_connect(url: String) -> Result<WebSocket, SeError> {
     let mut request = url.into_client_request()?;
     let headers = request.headers_mut();
     headers.insert(ORIGIN, HeaderValue::from_static("https://chat.stackexchange.com"));
     headers.insert(USER_AGENT, HeaderValue::from_static(APP_USER_AGENT));
     let (ws, _) = connect_async(request).await?;
+    Ok(ws)
+}
+
+pub async fn on_ws_conn(ws: WebSocket, rooms: RoomHandlers, last_event_ids: LastEventIds) -> Result<(), SeError> {
     let (_write, mut read) = ws.split();
-    while let Some(message) = read.next().await {
-        let message = message?;
+    loop {
+        let message = match timeout(READ_TIMEOUT, read.next()).await {
+            Ok(Some(message)) => message?,
+            Ok(None) => break,
+            Err(_) => return Err(SeError::ReadTimeout(READ_TIMEOUT)),
+        };
         if let Message::Text(message) = message {
             let message = serde_json::from_str::<Value>(&message)?;
-            for (key, value) in message.as_object().unwrap() {
-                if key == room_key.as_str() {
-                    let e = value.as_object().unwrap().get("e");
-                    if let Some(e) = e {
-                        let e = e.as_array().unwrap();
-                        let e = e[0].clone();
-                        let event = serde_json::from_value::<ChatEventType>(e.clone());
-                        if let Ok(event) = event {
-                            let mut handlers = event_handlers.lock().await;
-                            for handler in handlers.iter_mut() {
-                                handler(event.clone()).await;
-                            }
-                        } else {
-                            panic!("Failed to parse event: {}", e);
+            if let Some(message) = message.as_object() {
+                for (key, value) in message {
+                    // each key is "r<room id>", and holds the events for that room in "e"
+                    let room_id = key.strip_prefix('r').and_then(|id| id.parse::<u64>().ok());
+                    let events = value.get("e").and_then(Value::as_array);
+                    if let (Some(room_id), Some(events)) = (room_id, events) {
+                        let handlers = rooms.lock().await.get(&room_id).cloned();
+                        if let Some(handlers) = handlers {
+                            let mut last_event_ids = last_event_ids.lock().await;
+                            let last_event_id = last_event_ids.entry(room_id).or_default();
+                            dispatch_events(events, &handlers, last_event_id).await;
                         }
                     }
-                    break;
                 }
             }
         } else if let Message::Close(_) = message {
@@ -90,4 +332,97 @@ pub async fn on_ws_conn(url: String, room_id: u64, event_handlers: EventHandlers
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Runs the handlers on every event newer than `last_event_id`, in order, and then advances it.
+pub(crate) async fn dispatch_events(events: &[Value], event_handlers: &EventHandlers, last_event_id: &mut u64) {
+    let mut handlers = event_handlers.lock().await;
+    for event in events {
+        let id = event.get("id").and_then(Value::as_u64).unwrap_or(0);
+        if id != 0 && id <= *last_event_id {
+            continue;
+        }
+        if let Ok(event) = serde_json::from_value::<ChatEventType>(event.clone()) {
+            for handler in handlers.iter_mut() {
+                handler(event.clone()).await;
+            }
+        }
+        *last_event_id = id.max(*last_event_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(json: &str) -> ChatEventType {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn decodes_message() {
+        let event = decode(r#"{"content":"test","event_type":1,"id":141800943,"message_id":63567474,"room_id":1,"room_name":"Sandbox","time_stamp":1684029252,"user_id":526756,"user_name":"Seggan"}"#);
+        match event {
+            ChatEventType::Message { event, content, parent_id, .. } => {
+                assert_eq!(event.id, 141800943);
+                assert_eq!(event.message_id, 63567474);
+                assert_eq!(event.room_id, 1);
+                assert_eq!(event.timestamp, Duration::from_secs(1684029252));
+                assert_eq!(event.user_id, 526756);
+                assert_eq!(event.username, "Seggan");
+                assert_eq!(content, "test");
+                assert_eq!(parent_id, None);
+            }
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_edit() {
+        let event = decode(r#"{"content":"test (edit again)","event_type":2,"id":141800944,"message_edits":1,"message_id":63567474,"room_id":1,"room_name":"Sandbox","time_stamp":1684029252,"user_id":526756,"user_name":"Seggan"}"#);
+        match event {
+            ChatEventType::Edit { event, message_edits, content } => {
+                assert_eq!(event.message_id, 63567474);
+                assert_eq!(message_edits, 1);
+                assert_eq!(content, "test (edit again)");
+            }
+            other => panic!("expected an edit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_delete() {
+        let event = decode(r#"{"event_type":10,"id":141800967,"message_id":63567485,"room_id":1,"room_name":"Sandbox","time_stamp":1684029470,"user_id":526756,"user_name":"Seggan"}"#);
+        assert!(matches!(event, ChatEventType::Delete { event } if event.message_id == 63567485));
+    }
+
+    #[test]
+    fn decodes_reply() {
+        let event = decode(r#"{"content":"hi","event_type":18,"id":5,"message_id":6,"parent_id":4,"room_id":1,"target_user_id":7,"time_stamp":1684029470,"user_id":8,"user_name":"someone"}"#);
+        assert!(matches!(event, ChatEventType::Reply { parent_id: 4, target_user_id: 7, .. }));
+    }
+
+    #[test]
+    fn decodes_user_entered_without_message_fields() {
+        let event = decode(r#"{"event_type":3,"id":5,"room_id":1,"time_stamp":1684029470,"user_id":8,"user_name":"someone"}"#);
+        assert!(matches!(event, ChatEventType::UserEntered { user_id: 8, ref username, .. } if username == "someone"));
+    }
+
+    #[test]
+    fn keeps_unknown_event_types() {
+        let event = decode(r#"{"event_type":99,"id":5,"room_id":1,"time_stamp":1684029470}"#);
+        assert!(matches!(event, ChatEventType::Other { event_type: 99, .. }));
+    }
+
+    #[test]
+    fn keeps_malformed_known_events() {
+        // a message without its content
+        let event = decode(r#"{"event_type":1,"id":5,"message_id":6,"room_id":1,"time_stamp":1684029470,"user_id":8,"user_name":"someone"}"#);
+        assert!(matches!(event, ChatEventType::Other { event_type: 1, .. }));
+    }
+
+    #[test]
+    fn rejects_events_without_a_type() {
+        assert!(serde_json::from_str::<ChatEventType>(r#"{"id":5}"#).is_err());
+    }
+}
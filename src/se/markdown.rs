@@ -0,0 +1,218 @@
+/// Renders a message's markdown source into the HTML the server turns it into.
+///
+/// Single line messages support `**bold**`, `*italic*`, `---strikethrough---`, `` `code` ``,
+/// `[links](url)`, `[tag:tags]` and bare URLs. Multi-line messages aren't formatted, except that
+/// indenting every line by four spaces makes the whole message fixed font.
+pub fn to_html(source: &str) -> String {
+    let (_, source) = split_reply(source);
+    if !source.contains('\n') {
+        return inline(source);
+    }
+    if is_fixed_font(source) {
+        let lines = source.lines()
+            .map(|line| escape(line.strip_prefix("    ").or_else(|| line.strip_prefix('\t')).unwrap_or(line)))
+            .collect::<Vec<String>>();
+        format!("<pre class='full'>{}</pre>", lines.join("\n"))
+    } else {
+        let lines = source.lines().map(escape).collect::<Vec<String>>();
+        format!("<div class='full'>{}</div>", lines.join("<br>"))
+    }
+}
+
+/// Whether every line is indented by four spaces or a tab, which makes a message fixed font.
+pub fn is_fixed_font(source: &str) -> bool {
+    source.lines().all(|line| line.starts_with("    ") || line.starts_with('\t'))
+}
+
+/// Indents every line by four spaces, or removes the indent if it's already fixed font.
+pub fn toggle_fixed_font(source: &str) -> String {
+    let lines = if is_fixed_font(source) {
+        source.lines()
+            .map(|line| line.strip_prefix("    ").or_else(|| line.strip_prefix('\t')).unwrap_or(line).to_string())
+            .collect::<Vec<String>>()
+    } else {
+        source.lines().map(|line| format!("    {}", line)).collect()
+    };
+    lines.join("\n")
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Splits the `:id ` a reply starts with, which the server turns into the reply's parent, from
+/// the rest of the message. The id can also be on a line of its own.
+pub fn split_reply(source: &str) -> (Option<u64>, &str) {
+    if let Some(rest) = source.strip_prefix(':') {
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits > 0 && (rest[digits..].starts_with(' ') || rest[digits..].starts_with('\n')) {
+            return (rest[..digits].parse().ok(), &rest[digits + 1..]);
+        }
+    }
+    (None, source)
+}
+
+/// The delimiters of inline formatting and the tags they become, longest first so that `**`
+/// isn't mistaken for two `*`.
+const EMPHASIS: [(&str, &str); 5] = [("---", "strike"), ("**", "b"), ("__", "b"), ("*", "i"), ("_", "i")];
+
+fn inline(text: &str) -> String {
+    let mut html = String::new();
+    let mut rest = text;
+    let mut prev = None;
+    while let Some(c) = rest.chars().next() {
+        let formatted = code(rest)
+            .or_else(|| tag(rest))
+            .or_else(|| link(rest))
+            .or_else(|| url(rest, prev))
+            .or_else(|| emphasis(rest, prev));
+        match formatted {
+            Some((formatted, len)) => {
+                html.push_str(&formatted);
+                prev = rest[..len].chars().last();
+                rest = &rest[len..];
+            }
+            None => {
+                html.push_str(&escape(&c.to_string()));
+                prev = Some(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    html
+}
+
+/// Each of these gets the rest of the text if it starts with what they format, and returns the
+/// HTML for it and how many bytes of the text it used.
+fn code(text: &str) -> Option<(String, usize)> {
+    let inner = text.strip_prefix('`')?;
+    let end = inner.find('`')?;
+    (end > 0).then(|| (format!("<code>{}</code>", escape(&inner[..end])), end + 2))
+}
+
+fn tag(text: &str) -> Option<(String, usize)> {
+    let (inner, meta) = match text.strip_prefix("[tag:") {
+        Some(inner) => (inner, false),
+        None => (text.strip_prefix("[meta-tag:")?, true),
+    };
+    let end = inner.find(']')?;
+    let name = &inner[..end];
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    let class = if meta { "ob-post-tag meta-tag" } else { "ob-post-tag" };
+    let html = format!(
+        "<a href=\"/tags/{0}/info\"><span class=\"{1}\">{0}</span></a>",
+        escape(name),
+        class,
+    );
+    Some((html, text.len() - inner.len() + end + 1))
+}
+
+fn link(text: &str) -> Option<(String, usize)> {
+    let inner = text.strip_prefix('[')?;
+    let text_end = inner.find("](")?;
+    let target = &inner[text_end + 2..];
+    let target_end = target.find(')')?;
+    // a title can follow the URL, like [text](url "title")
+    let mut target_parts = target[..target_end].splitn(2, ' ');
+    let url = target_parts.next()?;
+    if text_end == 0 || !(url.starts_with("http://") || url.starts_with("https://") || url.starts_with("//")) {
+        return None;
+    }
+    let title = target_parts.next()
+        .map(|title| format!(" title=\"{}\"", escape(title.trim().trim_matches('"'))))
+        .unwrap_or_default();
+    let html = format!("<a href=\"{}\"{}>{}</a>", escape(url), title, inline(&inner[..text_end]));
+    Some((html, 1 + text_end + 2 + target_end + 1))
+}
+
+fn url(text: &str, prev: Option<char>) -> Option<(String, usize)> {
+    if !(text.starts_with("http://") || text.starts_with("https://")) || prev.is_some_and(|c| !c.is_whitespace()) {
+        return None;
+    }
+    let len = text.find(char::is_whitespace).unwrap_or(text.len());
+    // punctuation ending a sentence isn't part of the link
+    let url = text[..len].trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+    Some((format!("<a href=\"{0}\">{0}</a>", escape(url)), url.len()))
+}
+
+fn emphasis(text: &str, prev: Option<char>) -> Option<(String, usize)> {
+    let (delimiter, tag) = EMPHASIS.iter().find(|(delimiter, _)| text.starts_with(delimiter))?;
+    // underscores inside words, like in snake_case, aren't formatting
+    let word_bound = delimiter.starts_with('_');
+    if word_bound && prev.is_some_and(char::is_alphanumeric) {
+        return None;
+    }
+    let inner = &text[delimiter.len()..];
+    if inner.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let end = inner.match_indices(delimiter)
+        .map(|(i, _)| i)
+        .find(|&i| {
+            i > 0
+                && !inner[..i].ends_with(char::is_whitespace)
+                && !(word_bound && inner[i + delimiter.len()..].starts_with(char::is_alphanumeric))
+        })?;
+    // in a run of delimiters ending a word, like in ***both***, the outer formatting closes last
+    let delimiter_char = delimiter.chars().next()?;
+    let after_run = inner[end..].trim_start_matches(delimiter_char);
+    let end = if after_run.starts_with(char::is_alphanumeric) {
+        end
+    } else {
+        inner.len() - after_run.len() - delimiter.len()
+    };
+    let html = format!("<{1}>{0}</{1}>", inline(&inner[..end]), tag);
+    Some((html, delimiter.len() * 2 + end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_inline() {
+        assert_eq!(to_html("**bold** *italic* ---gone---"), "<b>bold</b> <i>italic</i> <strike>gone</strike>");
+        assert_eq!(to_html("`a *b* <c>`"), "<code>a *b* &lt;c&gt;</code>");
+        assert_eq!(to_html("[tag:rust]"), "<a href=\"/tags/rust/info\"><span class=\"ob-post-tag\">rust</span></a>");
+        assert_eq!(to_html("[site](https://example.com)"), "<a href=\"https://example.com\">site</a>");
+        assert_eq!(to_html("see https://example.com."), "see <a href=\"https://example.com\">https://example.com</a>.");
+    }
+
+    #[test]
+    fn nests_formatting() {
+        assert_eq!(to_html("***both***"), "<b><i>both</i></b>");
+        assert_eq!(to_html("**a *b* c**"), "<b>a <i>b</i> c</b>");
+    }
+
+    #[test]
+    fn leaves_unformatted_text() {
+        assert_eq!(to_html("snake_case_name"), "snake_case_name");
+        assert_eq!(to_html("2 * 3 * 4"), "2 * 3 * 4");
+        assert_eq!(to_html("a <b> & c"), "a &lt;b&gt; &amp; c");
+    }
+
+    #[test]
+    fn renders_multi_line_messages() {
+        assert_eq!(to_html("a\n**b**"), "<div class='full'>a<br>**b**</div>");
+        assert_eq!(to_html("    fn a() {}\n    <b>"), "<pre class='full'>fn a() {}\n&lt;b&gt;</pre>");
+    }
+
+    #[test]
+    fn strips_reply_prefix() {
+        assert_eq!(split_reply(":123 hi"), (Some(123), "hi"));
+        assert_eq!(split_reply(":123\n    code"), (Some(123), "    code"));
+        assert_eq!(split_reply(":abc hi"), (None, ":abc hi"));
+        assert_eq!(to_html(":123 **hi**"), "<b>hi</b>");
+    }
+
+    #[test]
+    fn toggles_fixed_font() {
+        assert_eq!(toggle_fixed_font("a\nb"), "    a\n    b");
+        assert_eq!(toggle_fixed_font("    a\n\tb"), "a\nb");
+    }
+}
@@ -6,8 +6,17 @@
 mod user;
 mod error;
 mod room;
+mod connection;
+mod store;
+mod split;
+mod ping;
 pub mod event;
+pub mod markdown;
 
 pub use user::*;
 pub use error::*;
-pub use room::*;
\ No newline at end of file
+pub use room::*;
+pub use connection::*;
+pub use store::*;
+pub use split::*;
+pub use ping::*;
\ No newline at end of file
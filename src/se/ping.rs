@@ -0,0 +1,100 @@
+/// Pings need at least this many characters of the name after the `@`.
+const MIN_PING_LENGTH: usize = 3;
+
+/// The name a user is pinged by, which is their display name without spaces.
+pub fn ping_name(username: &str) -> String {
+    username.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Whether `text` pings the user called `username`.
+///
+/// Like on the server, a ping is an `@` followed by the start of the user's [`ping_name`], ignoring
+/// case, which is at least [`MIN_PING_LENGTH`] characters long.
+pub fn pings(text: &str, username: &str) -> bool {
+    let name = ping_name(username).to_lowercase();
+    text.match_indices('@')
+        .filter(|&(i, _)| starts_ping(text, i))
+        .any(|(i, _)| {
+            let ping = text[i + 1..].split(char::is_whitespace).next().unwrap_or_default();
+            // punctuation after a ping, like in "@name, hi", isn't part of it
+            let ping = ping.trim_end_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+            ping.chars().count() >= MIN_PING_LENGTH && name.starts_with(&ping)
+        })
+}
+
+/// Finds the ping being typed before the byte offset `cursor`, returning where the name after its
+/// `@` starts and what of it is typed so far.
+pub fn ping_before(content: &str, cursor: usize) -> Option<(usize, &str)> {
+    let word_start = content[..cursor].char_indices()
+        .rev()
+        .find(|&(_, c)| c.is_whitespace())
+        .map_or(0, |(i, c)| i + c.len_utf8());
+    let at = word_start + content[word_start..cursor].rfind('@')?;
+    if !starts_ping(content, at) {
+        return None;
+    }
+    Some((at + 1, &content[at + 1..cursor]))
+}
+
+/// Whether the `@` at byte `i` of `text` can start a ping. One in the middle of a word, like in an
+/// email address, can't.
+fn starts_ping(text: &str, i: usize) -> bool {
+    !text[..i].ends_with(char::is_alphanumeric)
+}
+
+/// The ping names of `usernames` that start with `prefix`, ignoring case, in the same order and
+/// without duplicates.
+pub fn ping_completions(prefix: &str, usernames: &[String]) -> Vec<String> {
+    let prefix = prefix.to_lowercase();
+    let mut completions = Vec::new();
+    for name in usernames.iter().map(|username| ping_name(username)) {
+        if name.to_lowercase().starts_with(&prefix) && !completions.contains(&name) {
+            completions.push(name);
+        }
+    }
+    completions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pings_by_name_prefix() {
+        assert!(pings("@Seggan hi", "Seggan"));
+        assert!(pings("hi @seg", "Seggan"));
+        assert!(pings("@JohnDoe, look", "John Doe"));
+        assert!(!pings("@se hi", "Seggan"));
+        assert!(!pings("@Seggans hi", "Seggan"));
+        assert!(!pings("mail me@seggan.com", "Seggan"));
+        assert!(!pings("no ping here", "Seggan"));
+    }
+
+    #[test]
+    fn finds_ping_before_cursor() {
+        assert_eq!(ping_before("hi @Seg", 7), Some((4, "Seg")));
+        assert_eq!(ping_before("@Seg", 4), Some((1, "Seg")));
+        assert_eq!(ping_before("hi @Seg there", 6), Some((4, "Se")));
+        assert_eq!(ping_before("hi @", 4), Some((4, "")));
+        assert_eq!(ping_before("me@seggan", 9), None);
+        assert_eq!(ping_before("hi Seg", 6), None);
+        assert_eq!(ping_before("@Seg hi", 7), None);
+    }
+
+    #[test]
+    fn finds_ping_after_non_ascii_whitespace() {
+        let content = "hi\u{3000}@Seg";
+        assert_eq!(ping_before(content, content.len()), Some((6, "Seg")));
+        let content = "hi\u{a0}@Seg";
+        assert_eq!(ping_before(content, content.len()), Some((5, "Seg")));
+        let content = "héllo\u{3000}@ü";
+        assert_eq!(ping_before(content, content.len()), Some((content.len() - 2, "ü")));
+    }
+
+    #[test]
+    fn completes_ping_names() {
+        let usernames = vec![String::from("John Doe"), String::from("jo"), String::from("Bob"), String::from("John Doe")];
+        assert_eq!(ping_completions("jo", &usernames), vec![String::from("JohnDoe"), String::from("jo")]);
+        assert_eq!(ping_completions("x", &usernames), Vec::<String>::new());
+    }
+}
@@ -1,22 +1,24 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, SystemTime};
 
 use reqwest::{Client, Response, StatusCode};
 use reqwest_cookie_store::CookieStoreMutex;
 use select::document::Document;
-use select::predicate::{Class, Name, Predicate};
+use select::predicate::{Attr, Class, Name, Predicate};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_with::serde_as;
-use tokio::sync::Mutex;
-use tokio::task::JoinHandle;
+use tokio::sync::{broadcast, watch, Mutex};
+use tokio::time::sleep;
 
 use crate::app::APP_USER_AGENT;
-use crate::se::event::{ChatEventType, on_ws_conn};
-use crate::se::SeError;
+use crate::se::event::ChatEventType;
+use crate::se::{fit_message, fit_reply, pings, LongMessage, MessageStore, MessageUpdate, SeError, MESSAGE_LIMIT};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct RoomSpec {
@@ -27,18 +29,66 @@ pub struct RoomSpec {
 pub type EventHandlers =
 Arc<Mutex<Vec<Box<dyn FnMut(ChatEventType) -> Pin<Box<dyn Future<Output=()> + Send + 'static>> + Send>>>>;
 
+/// The event handlers of every room listening on a websocket, keyed by room id.
+pub type RoomHandlers = Arc<Mutex<HashMap<u64, EventHandlers>>>;
+
+/// How long after sending a message it can still be edited.
+pub const EDIT_WINDOW: Duration = Duration::from_secs(120);
+
+/// How many message updates can be queued for a slow subscriber before it starts missing them.
+const UPDATE_CAPACITY: usize = 256;
+
+/// How long to wait when rate limited by a response that doesn't say for how long.
+const DEFAULT_RATE_LIMIT: Duration = Duration::from_secs(5);
+
+/// A joined room. Clones share the same cache and updates, so one can be used without holding
+/// on to the [`User`](crate::se::User) it came from.
+#[derive(Clone)]
 pub struct Room {
     client: Arc<Client>,
     fkey: String,
     user_id: u64,
+    /// Our display name, which pending messages are shown with.
+    username: String,
+    /// The newest event loaded when the room was joined, which live events carry on from.
+    loaded_event_id: u64,
     room_id: u64,
-    messages: Arc<Mutex<Vec<Message>>>,
+    messages: Arc<Mutex<MessageStore>>,
+    /// The text of messages fetched individually because they weren't loaded, such as old parents,
+    /// or `None` if fetching them failed.
+    fetched: Arc<Mutex<HashMap<u64, Option<String>>>>,
+    updates: broadcast::Sender<MessageUpdate>,
+    outbox: Arc<Mutex<Outbox>>,
+    /// How many messages are in the outbox.
+    queued: Arc<watch::Sender<usize>>,
+    unread: Arc<watch::Sender<Unread>>,
+    /// Messages that ping us, as they arrive.
+    pings: broadcast::Sender<Message>,
+    /// Who's been in the room recently, most recently seen first.
+    users: Arc<watch::Sender<Vec<RoomUser>>>,
+    /// Whether the room is being viewed, in which case nothing is counted as unread.
+    viewed: Arc<AtomicBool>,
     event_handlers: EventHandlers,
-    task: JoinHandle<()>,
+}
+
+/// The messages that arrived in a room while it wasn't being viewed.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Unread {
+    pub messages: usize,
+    /// Messages that mention or reply to us.
+    pub mentions: usize,
+}
+
+/// Our messages waiting to be sent, by local id, in the order they were sent in.
+#[derive(Default)]
+struct Outbox {
+    queue: VecDeque<u64>,
+    /// Whether a task is sending the queue's messages, which stops when it's empty.
+    flushing: bool,
 }
 
 impl Room {
-    pub async fn new(cookies: Arc<CookieStoreMutex>, fkey: String, user_id: u64, room_id: u64) -> Self {
+    pub async fn new(cookies: Arc<CookieStoreMutex>, fkey: String, user_id: u64, username: String, room_id: u64) -> Result<Self, SeError> {
         let client = Arc::new(Client::builder()
             .user_agent(APP_USER_AGENT)
             .cookie_store(true)
@@ -46,69 +96,545 @@ impl Room {
             .build()
             .unwrap()
         );
-        let event_handlers = Arc::new(Mutex::new(Vec::new()));
-        let moved_client = client.clone();
-        let moved_fkey = fkey.clone();
-        let moved_event_handlers = event_handlers.clone();
-        let task = tokio::spawn(async move {
-            let client = moved_client;
-            loop {
-                let response = client.post("https://chat.stackexchange.com/ws-auth")
-                    .form(&[("roomid", room_id.to_string()), ("fkey", moved_fkey.clone())])
-                    .send()
-                    .await
-                    .unwrap()
-                    .json::<Value>()
-                    .await
-                    .unwrap();
-                if let Value::Object(obj) = response {
-                    if let Value::String(url) = &obj["url"] {
-                        let url = format!(
-                            "{}?l={}",
-                            url,
-                            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
-                        );
-                        on_ws_conn(url, room_id, moved_event_handlers.clone()).await.unwrap();
-                    }
-                }
-            }
-        });
-        let ret = Self {
+        let mut ret = Self {
             client,
             fkey,
             user_id,
+            username,
+            loaded_event_id: 0,
             room_id,
-            messages: Arc::new(Mutex::new(Vec::new())),
-            event_handlers,
-            task,
+            messages: Arc::new(Mutex::new(MessageStore::new())),
+            fetched: Arc::new(Mutex::new(HashMap::new())),
+            updates: broadcast::channel(UPDATE_CAPACITY).0,
+            outbox: Arc::new(Mutex::new(Outbox::default())),
+            queued: Arc::new(watch::channel(0).0),
+            unread: Arc::new(watch::channel(Unread::default()).0),
+            pings: broadcast::channel(UPDATE_CAPACITY).0,
+            users: Arc::new(watch::channel(Vec::new()).0),
+            viewed: Arc::new(AtomicBool::new(false)),
+            event_handlers: Arc::new(Mutex::new(Vec::new())),
         };
         let messages = ret.messages.clone();
+        let updates = ret.updates.clone();
+        let pings = ret.pings.clone();
+        let unread = ret.unread.clone();
+        let viewed = ret.viewed.clone();
+        let username = ret.username.clone();
         ret.register_handler(move |event| {
             let messages = messages.clone();
+            let updates = updates.clone();
+            let pings = pings.clone();
+            let unread = unread.clone();
+            let viewed = viewed.clone();
+            let username = username.clone();
             async move {
-                if let Ok(message) = event.try_into() {
-                    let mut messages = messages.lock().await;
-                    if !messages.contains(&message) {
-                        messages.push(message);
+                let mut messages = messages.lock().await;
+                let mut arrived = Unread::default();
+                let changed = match event {
+                    ChatEventType::Edit { event, content, .. } => {
+                        messages.get_mut(event.message_id).map(|message| {
+                            message.content = content;
+                            message.clone()
+                        })
+                    }
+                    ChatEventType::MessageStarred { message_id, stars, owner_stars, .. } => {
+                        messages.get_mut(message_id).map(|message| {
+                            message.stars = stars;
+                            message.owner_stars = owner_stars;
+                            message.clone()
+                        })
+                    }
+                    ChatEventType::Delete { event } => {
+                        messages.get_mut(event.message_id).map(|message| {
+                            message.content.clear();
+                            message.deleted = true;
+                            message.clone()
+                        })
                     }
+                    // the server's own word that a message pings us, which also covers replies to
+                    // messages of ours that aren't loaded
+                    ChatEventType::Mention { event, target_user_id, .. } | ChatEventType::Reply { event, target_user_id, .. }
+                        if target_user_id == user_id => {
+                        messages.get_mut(event.message_id)
+                            .filter(|message| !message.mentioned)
+                            .map(|message| {
+                                message.mentioned = true;
+                                arrived.mentions += 1;
+                                message.clone()
+                            })
+                    }
+                    event => match Message::try_from(event) {
+                        Ok(mut message) => {
+                            let own = message.user_id == user_id;
+                            message.mentioned = !own && (
+                                pings_us(&messages, &message, user_id, &username)
+                                    || messages.get(message.id).is_some_and(|old| old.mentioned)
+                            );
+                            // this also replaces a confirmed message of ours with the server's version
+                            if messages.insert(message.clone()) && !own {
+                                arrived.messages += 1;
+                                if message.mentioned {
+                                    arrived.mentions += 1;
+                                }
+                            }
+                            Some(message)
+                        }
+                        _ => None,
+                    }
+                };
+                if let Some(message) = changed {
+                    if arrived.mentions > 0 {
+                        let _ = pings.send(message.clone());
+                    }
+                    // nobody listening is fine
+                    let _ = updates.send(MessageUpdate::Changed(message));
+                }
+                if arrived != Unread::default() && !viewed.load(Ordering::Relaxed) {
+                    unread.send_modify(|unread| {
+                        unread.messages += arrived.messages;
+                        unread.mentions += arrived.mentions;
+                    });
                 }
             }
         }).await;
-        ret
+        let users = ret.users.clone();
+        let client = ret.client.clone();
+        let fkey = ret.fkey.clone();
+        ret.register_handler(move |event| {
+            let seen = match event {
+                ChatEventType::UserEntered { event, user_id, username } => Some((user_id, username, event.timestamp)),
+                ChatEventType::Message { event, .. } => Some((event.user_id, event.username, event.timestamp)),
+                ChatEventType::UserLeft { user_id, .. } => {
+                    users.send_modify(|users| users.retain(|user| user.id != user_id));
+                    None
+                }
+                _ => None,
+            };
+            let mut new = None;
+            if let Some((user_id, username, timestamp)) = seen {
+                users.send_modify(|users| {
+                    let user = match users.iter().position(|user| user.id == user_id) {
+                        Some(i) => RoomUser { username, last_seen: Some(timestamp), ..users.remove(i) },
+                        None => {
+                            new = Some(user_id);
+                            RoomUser { id: user_id, username, reputation: None, last_seen: Some(timestamp), moderator: false, owner: false }
+                        }
+                    };
+                    users.insert(0, user);
+                });
+            }
+            if let Some(user_id) = new {
+                let users = users.clone();
+                let client = client.clone();
+                let fkey = fkey.clone();
+                // fetched separately, so other events don't wait for it
+                tokio::spawn(async move {
+                    // without it the user is just shown without their reputation
+                    if let Ok(info) = fetch_user_info(&client, &fkey, room_id, &[user_id]).await {
+                        users.send_modify(|users| apply_user_info(users, &info));
+                    }
+                });
+            }
+            async {}
+        }).await;
+        // fetching the room's events is also what makes the server consider us joined
+        ret.loaded_event_id = ret.get_prev_messages(100).await?;
+        Ok(ret)
+    }
+
+    /// Queues a message to be sent after the ones before it, and returns the local ids of the
+    /// messages it was sent as, which is several if it was too long and `long` splits it.
+    ///
+    /// Each message shows as pending in the cache until the server confirms it, waiting out rate
+    /// limits. If sending fails it stays in the cache marked as failed, so it can be
+    /// [retried](Room::retry), and so do the messages queued after it.
+    pub async fn send_message(&self, msg: &str, long: LongMessage) -> Vec<u64> {
+        let mut ids = Vec::new();
+        for part in fit_message(msg, long, MESSAGE_LIMIT) {
+            ids.push(self.queue_message(&part).await);
+        }
+        ids
+    }
+
+    /// Queues a message as a reply to `parent_id`, which only the first message is if it's split.
+    pub async fn reply(&self, parent_id: u64, msg: &str, long: LongMessage) -> Vec<u64> {
+        let mut ids = Vec::new();
+        for part in fit_reply(parent_id, msg, long) {
+            ids.push(self.queue_message(&part).await);
+        }
+        ids
+    }
+
+    async fn queue_message(&self, msg: &str) -> u64 {
+        let pending = self.messages.lock().await.add_pending(self.user_id, self.room_id, &self.username, msg);
+        let _ = self.updates.send(MessageUpdate::Changed(pending.clone()));
+        self.enqueue(pending.id).await;
+        pending.id
+    }
+
+    /// Sends a message that failed to send again, at the end of the queue. Does nothing if it
+    /// didn't fail.
+    pub async fn retry(&self, local_id: u64) {
+        match self.messages.lock().await.get_mut(local_id) {
+            Some(message) if matches!(message.state, MessageState::Failed(_)) => {
+                message.state = MessageState::Pending;
+                let _ = self.updates.send(MessageUpdate::Changed(message.clone()));
+            }
+            _ => return,
+        }
+        self.enqueue(local_id).await;
+    }
+
+    /// Removes a message that failed to send from the cache.
+    pub async fn discard(&self, local_id: u64) {
+        let mut messages = self.messages.lock().await;
+        if messages.get(local_id).is_some_and(|message| matches!(message.state, MessageState::Failed(_))) {
+            messages.remove(local_id);
+            let _ = self.updates.send(MessageUpdate::Removed(local_id));
+        }
+    }
+
+    /// Returns a receiver of how many messages are waiting to be sent, including the one being sent.
+    pub fn queued(&self) -> watch::Receiver<usize> {
+        self.queued.subscribe()
+    }
+
+    /// Waits until every queued message has been sent, or failed to send.
+    pub async fn sent(&self) {
+        // the sender lives as long as the room, so this can't fail
+        let _ = self.queued.subscribe().wait_for(|&queued| queued == 0).await;
+    }
+
+    /// Returns a receiver of how many messages arrived while the room wasn't being viewed.
+    pub fn unread(&self) -> watch::Receiver<Unread> {
+        self.unread.subscribe()
+    }
+
+    /// Fetches everyone who's been in the room recently, which are the users that can be pinged,
+    /// along with their reputation. Afterwards users coming and going keep the list up to date.
+    pub async fn users(&self) -> Result<Vec<RoomUser>, SeError> {
+        let response = self.client.get(format!("https://chat.stackexchange.com/rooms/pingable/{}", self.room_id))
+            .send()
+            .await?
+            .json::<Value>()
+            .await?;
+        // each user is an array of their id, name and when they were last seen
+        let mut users = response.as_array()
+            .map(|users| users.iter()
+                .filter_map(|user| Some(RoomUser {
+                    id: user[0].as_u64()?,
+                    username: user[1].as_str()?.to_string(),
+                    reputation: None,
+                    last_seen: user[2].as_u64().map(Duration::from_secs),
+                    moderator: false,
+                    owner: false,
+                }))
+                .collect::<Vec<RoomUser>>()
+            )
+            .unwrap_or_default();
+        let ids = users.iter().map(|user| user.id).collect::<Vec<u64>>();
+        if !ids.is_empty() {
+            let info = fetch_user_info(&self.client, &self.fkey, self.room_id, &ids).await?;
+            apply_user_info(&mut users, &info);
+        }
+        users.sort_by_key(|user| Reverse(user.last_seen));
+        self.users.send_replace(users.clone());
+        Ok(users)
     }
 
-    pub async fn send_message(&self, msg: &str) -> Result<u64, SeError> {
+    /// Returns a receiver of who's been in the room recently, which starts out empty until
+    /// [`Room::users`] fetches them.
+    pub fn subscribe_users(&self) -> watch::Receiver<Vec<RoomUser>> {
+        self.users.subscribe()
+    }
+
+    /// Returns a receiver of every message that pings us as it arrives, whether the room is being
+    /// viewed or not.
+    pub fn subscribe_pings(&self) -> broadcast::Receiver<Message> {
+        self.pings.subscribe()
+    }
+
+    /// Sets whether the room is being viewed. Viewing it marks everything in it read.
+    pub fn set_viewed(&self, viewed: bool) {
+        self.viewed.store(viewed, Ordering::Relaxed);
+        if viewed {
+            self.unread.send_replace(Unread::default());
+        }
+    }
+
+    async fn enqueue(&self, local_id: u64) {
+        let mut outbox = self.outbox.lock().await;
+        outbox.queue.push_back(local_id);
+        self.queued.send_replace(outbox.queue.len());
+        if !outbox.flushing {
+            outbox.flushing = true;
+            let room = self.clone();
+            tokio::spawn(async move {
+                let flush = room.clone();
+                // if sending panics, the queue would otherwise wait for it forever
+                if tokio::spawn(async move { flush.flush().await }).await.is_err() {
+                    let mut outbox = room.outbox.lock().await;
+                    room.fail_queue(&mut outbox, "Sending failed").await;
+                }
+            });
+        }
+    }
+
+    /// Sends the queued messages one at a time until there are none left. If one fails, the rest
+    /// fail too, so that they're never sent out of order.
+    async fn flush(&self) {
+        loop {
+            let local_id = {
+                let mut outbox = self.outbox.lock().await;
+                match outbox.queue.front() {
+                    Some(&local_id) => local_id,
+                    None => {
+                        outbox.flushing = false;
+                        return;
+                    }
+                }
+            };
+            let sent = self.deliver(local_id).await;
+            let mut outbox = self.outbox.lock().await;
+            outbox.queue.pop_front();
+            if !sent {
+                self.fail_queue(&mut outbox, "Not sent, since an earlier message failed").await;
+                return;
+            }
+            self.queued.send_replace(outbox.queue.len());
+        }
+    }
+
+    /// Marks every queued message as failed with `reason` and empties the queue, which stops it
+    /// being sent.
+    async fn fail_queue(&self, outbox: &mut Outbox, reason: &str) {
+        let mut messages = self.messages.lock().await;
+        for local_id in outbox.queue.drain(..) {
+            if let Some(message) = messages.get_mut(local_id) {
+                message.state = MessageState::Failed(reason.to_string());
+                let _ = self.updates.send(MessageUpdate::Changed(message.clone()));
+            }
+        }
+        outbox.flushing = false;
+        self.queued.send_replace(0);
+    }
+
+    /// Sends a queued message, and returns whether it was sent. A message that was discarded
+    /// while it was queued counts as sent, since there's nothing left to send.
+    async fn deliver(&self, local_id: u64) -> bool {
+        let content = match self.messages.lock().await.get(local_id) {
+            Some(message) => message.content.clone(),
+            None => return true,
+        };
+        let result = loop {
+            match self.post_message(&content).await {
+                Err(SeError::RateLimit(wait)) => sleep(wait).await,
+                result => break result,
+            }
+        };
+        let mut messages = self.messages.lock().await;
+        match result {
+            Ok(id) => {
+                let _ = self.updates.send(MessageUpdate::Removed(local_id));
+                if let Some(message) = messages.confirm(local_id, id) {
+                    let _ = self.updates.send(MessageUpdate::Changed(message));
+                }
+                true
+            }
+            Err(error) => {
+                if let Some(message) = messages.get_mut(local_id) {
+                    message.state = MessageState::Failed(error.to_string());
+                    let _ = self.updates.send(MessageUpdate::Changed(message.clone()));
+                }
+                false
+            }
+        }
+    }
+
+    async fn post_message(&self, msg: &str) -> Result<u64, SeError> {
         let response = self.request(
             format!("https://chat.stackexchange.com/chats/{}/messages/new", self.room_id),
             [("text", msg)].into(),
+        ).await?;
+        let status = response.status().as_u16();
+        let response = response.json::<Value>().await?;
+        response["id"].as_u64().ok_or_else(|| SeError::BadResponse(status, response.to_string()))
+    }
+
+    /// Edits one of our messages. The server only allows this within [`EDIT_WINDOW`] of sending it.
+    pub async fn edit_message(&self, id: u64, msg: &str) -> Result<(), SeError> {
+        if let Some(message) = self.messages.lock().await.get(id) {
+            if !message.is_editable() {
+                return Err(SeError::EditWindowExpired);
+            }
+        }
+        let response = self.request(
+            format!("https://chat.stackexchange.com/messages/{}", id),
+            [("text", msg)].into(),
         )
             .await?
-            .json::<Value>()
+            .text()
             .await?;
-        Ok(response["id"].as_u64().unwrap())
+        expect_ok(response)
     }
 
-    pub async fn get_prev_messages(&self, num_messages: usize) -> Result<(), SeError> {
+    /// Deletes one of our messages. Like editing, this is only allowed within [`EDIT_WINDOW`].
+    pub async fn delete_message(&self, id: u64) -> Result<(), SeError> {
+        let response = self.request(
+            format!("https://chat.stackexchange.com/messages/{}/delete", id),
+            [].into(),
+        )
+            .await?
+            .text()
+            .await?;
+        expect_ok(response)
+    }
+
+    /// Stars the message, or removes our star if we already starred it.
+    pub async fn toggle_star(&self, id: u64) -> Result<(), SeError> {
+        self.request(format!("https://chat.stackexchange.com/messages/{}/star", id), [].into()).await?;
+        Ok(())
+    }
+
+    /// Pins the message to the top of the starboard. Only room owners can do this.
+    pub async fn pin(&self, id: u64) -> Result<(), SeError> {
+        self.set_pinned(id, true).await
+    }
+
+    pub async fn unpin(&self, id: u64) -> Result<(), SeError> {
+        self.set_pinned(id, false).await
+    }
+
+    async fn set_pinned(&self, id: u64, pinned: bool) -> Result<(), SeError> {
+        // the server only has a toggle, so don't touch messages that are already as requested
+        let current = self.messages.lock().await.get(id).map(Message::is_pinned);
+        if current == Some(pinned) {
+            return Ok(());
+        }
+        self.request(format!("https://chat.stackexchange.com/messages/{}/owner-star", id), [].into()).await?;
+        Ok(())
+    }
+
+    /// Scrapes the starboard in the room's sidebar, pinned messages first.
+    pub async fn starred_messages(&self) -> Result<Vec<StarredMessage>, SeError> {
+        let response = self.client.get(format!("https://chat.stackexchange.com/rooms/{}", self.room_id))
+            .send()
+            .await?
+            .text()
+            .await?;
+        let document = Document::from(response.as_str());
+        let starred = document.find(Attr("id", "starred-posts").descendant(Name("li")))
+            .filter_map(|item| {
+                let id = item.attr("id")?.strip_prefix("summary_")?.parse().ok()?;
+                // a single star has no count shown
+                let stars = item.find(Class("times"))
+                    .next()
+                    .and_then(|times| times.text().trim().parse().ok())
+                    .unwrap_or(1);
+                let pinned = item.attr("class").is_some_and(|class| class.contains("owner-star"));
+                let username = item.find(Name("a"))
+                    .filter(|link| link.attr("href").is_some_and(|href| href.starts_with("/users/")))
+                    .last()
+                    .map(|link| link.text())
+                    .unwrap_or_default();
+                let text = item.children()
+                    .filter(|child| {
+                        let class = child.attr("class").unwrap_or_default();
+                        !["times", "img", "quick-unstar", "relativetime"].iter().any(|c| class.contains(c))
+                            && (!child.is(Name("a")) || child.text() != username)
+                    })
+                    .map(|child| child.text())
+                    .collect::<String>();
+                let text = text.trim().trim_end_matches('-').trim().to_string();
+                Some(StarredMessage { id, stars, pinned, username, text })
+            })
+            .collect();
+        Ok(starred)
+    }
+
+    /// Gets the markdown source of a message, as opposed to the rendered HTML in [`Message::content`].
+    pub async fn get_message_source(&self, id: u64) -> Result<String, SeError> {
+        let response = self.client.get(format!("https://chat.stackexchange.com/message/{}?plain=true", id))
+            .send()
+            .await?;
+        if response.status().is_success() {
+            Ok(response.text().await?)
+        } else {
+            Err(SeError::BadResponse(response.status().as_u16(), response.text().await?))
+        }
+    }
+
+    /// Gets the plain text of any message, fetching it if it isn't loaded. A message that couldn't
+    /// be fetched isn't tried again.
+    pub async fn get_message_text(&self, id: u64) -> Result<String, SeError> {
+        if let Some(text) = self.known_message_text(id).await {
+            return Ok(text);
+        }
+        if self.fetched.lock().await.contains_key(&id) {
+            return Err(SeError::MessageUnavailable(id));
+        }
+        let result = self.fetch_message_text(id).await;
+        self.fetched.lock().await.insert(id, result.as_ref().ok().cloned());
+        result
+    }
+
+    /// Gets the plain text of a message if it's loaded or was already fetched, without fetching it.
+    pub async fn known_message_text(&self, id: u64) -> Option<String> {
+        if let Some(message) = self.messages.lock().await.get(id) {
+            return Some(message.text());
+        }
+        self.fetched.lock().await.get(&id).cloned().flatten()
+    }
+
+    async fn fetch_message_text(&self, id: u64) -> Result<String, SeError> {
+        let response = self.client.get(format!("https://chat.stackexchange.com/message/{}", id))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(SeError::BadResponse(response.status().as_u16(), response.text().await?));
+        }
+        Ok(html_to_text(&response.text().await?))
+    }
+
+    /// The most recent message someone else sent in this room, if it's still loaded.
+    pub async fn last_other_message(&self) -> Option<Message> {
+        self.messages.lock().await
+            .iter()
+            .rev()
+            .find(|msg| msg.user_id != self.user_id && !msg.deleted)
+            .cloned()
+    }
+
+    /// The names of the users who sent the loaded messages, most recently active first, and then
+    /// of the rest of the room's users. We aren't included, since there's no reason to ping ourselves.
+    pub async fn recent_usernames(&self) -> Vec<String> {
+        let mut user_ids = Vec::new();
+        let mut usernames = Vec::new();
+        let messages = self.messages.lock().await;
+        let authors = messages.iter().rev().map(|message| (message.user_id, &message.username));
+        // people in the room who haven't said anything lately come after those who have
+        let users = self.users.borrow();
+        for (user_id, username) in authors.chain(users.iter().map(|user| (user.id, &user.username))) {
+            if user_id != self.user_id && !user_ids.contains(&user_id) {
+                user_ids.push(user_id);
+                usernames.push(username.clone());
+            }
+        }
+        usernames
+    }
+
+    /// The most recent message we sent in this room and haven't deleted, if it's still loaded.
+    pub async fn last_own_message(&self) -> Option<Message> {
+        self.messages.lock().await
+            .iter()
+            .rev()
+            .find(|msg| msg.user_id == self.user_id && msg.state == MessageState::Sent && !msg.deleted)
+            .cloned()
+    }
+
+    /// Loads the newest `num_messages` messages into the cache, returning the id of the newest
+    /// event, or 0 if the room has none.
+    pub async fn get_prev_messages(&self, num_messages: usize) -> Result<u64, SeError> {
         let response = self.request(
             format!("https://chat.stackexchange.com/chats/{}/events", self.room_id),
             [("mode", "Messages"), ("msgCount", num_messages.to_string().as_str()), ("since", "0")].into(),
@@ -116,28 +642,67 @@ impl Room {
             .await?
             .json::<Value>()
             .await?;
-        let events = response.as_object().unwrap()["events"].as_array().unwrap();
+        self.merge_events(&response).await;
+        let newest = response["events"].as_array()
+            .and_then(|events| events.iter().filter_map(|event| event["id"].as_u64()).max())
+            .unwrap_or(0);
+        Ok(newest)
+    }
+
+    /// Loads up to `num_messages` messages sent before `message_id` into the cache, returning how
+    /// many weren't already in it. Zero means the start of the room's history was reached.
+    pub async fn load_before(&self, message_id: u64, num_messages: usize) -> Result<usize, SeError> {
+        let response = self.request(
+            format!("https://chat.stackexchange.com/chats/{}/events", self.room_id),
+            [
+                ("mode", "Messages"),
+                ("msgCount", num_messages.to_string().as_str()),
+                ("before", message_id.to_string().as_str()),
+            ].into(),
+        )
+            .await?
+            .json::<Value>()
+            .await?;
+        Ok(self.merge_events(&response).await)
+    }
 
-        let new = events.into_iter()
-            .filter_map(|event| serde_json::from_value::<Message>(event.clone()).ok())
-            .collect::<Vec<Message>>();
+    /// Merges the messages in an events response into the cache. Returns how many messages
+    /// weren't already cached.
+    async fn merge_events(&self, response: &Value) -> usize {
+        let new = response["events"].as_array()
+            .map(|events| events.iter()
+                .filter_map(|event| serde_json::from_value::<Message>(event.clone()).ok())
+                .collect::<Vec<Message>>()
+            )
+            .unwrap_or_default();
 
         let mut messages = self.messages.lock().await;
-        messages.retain(|msg| !new.contains(msg));
-        messages.extend(new);
-
-        Ok(())
+        let mut added = 0;
+        for mut message in new {
+            message.mentioned = message.user_id != self.user_id
+                && pings_us(&messages, &message, self.user_id, &self.username);
+            if messages.insert(message.clone()) {
+                added += 1;
+            }
+            let _ = self.updates.send(MessageUpdate::Changed(message));
+        }
+        added
     }
 
+    /// The loaded messages, oldest first. The newest ones are loaded when the room is joined, so
+    /// this doesn't fetch anything.
     pub async fn get_messages(&self) -> Vec<Message> {
-        {
-            let messages = self.messages.lock().await;
-            if messages.is_empty() {
-                drop(messages);
-                self.get_prev_messages(100).await.unwrap();
-            }
-        }
-        self.messages.lock().await.clone()
+        self.messages.lock().await.iter().cloned().collect()
+    }
+
+    /// Returns a receiver of every message that is added to the room's cache, or changes in it.
+    pub fn subscribe(&self) -> broadcast::Receiver<MessageUpdate> {
+        self.updates.subscribe()
+    }
+
+    /// Gets a message from the cache.
+    pub async fn get_message(&self, id: u64) -> Option<Message> {
+        self.messages.lock().await.get(id).cloned()
     }
 
     pub async fn register_handler<F>(&self, mut handler: impl FnMut(ChatEventType) -> F + Send + 'static)
@@ -158,7 +723,7 @@ impl Room {
             if res.status().is_success() {
                 Ok(res)
             } else if res.status() == StatusCode::CONFLICT {
-                Err(SeError::RateLimit)
+                Err(SeError::RateLimit(retry_after(&res.text().await?).unwrap_or(DEFAULT_RATE_LIMIT)))
             } else {
                 Err(SeError::BadResponse(res.status().as_u16(), res.text().await.unwrap()))
             }
@@ -167,21 +732,124 @@ impl Room {
         };
     }
 
-    pub async fn leave(self) {
+    /// Tells the server we left the room, so we stop showing up in its user list.
+    pub async fn leave(self) -> Result<(), SeError> {
         self.request(
             format!("https://chat.stackexchange.com/chats/leave/{}", self.room_id),
-            [].into(),
-        ).await.unwrap();
+            [("quiet", "true")].into(),
+        ).await?;
+        Ok(())
     }
 
     pub fn get_id(&self) -> u64 {
         self.room_id
     }
+
+    /// The id of the user we're in the room as.
+    pub fn get_user_id(&self) -> u64 {
+        self.user_id
+    }
+
+    /// The id of the newest event loaded when the room was joined.
+    pub(crate) fn loaded_event_id(&self) -> u64 {
+        self.loaded_event_id
+    }
+
+    pub(crate) fn event_handlers(&self) -> EventHandlers {
+        self.event_handlers.clone()
+    }
+}
+
+/// Whether a message pings us, by replying to one of our messages or mentioning us by name.
+fn pings_us(messages: &MessageStore, message: &Message, user_id: u64, username: &str) -> bool {
+    message.parent_id
+        .and_then(|parent_id| messages.get(parent_id))
+        .is_some_and(|parent| parent.user_id == user_id)
+        || pings(&message.text(), username)
 }
 
-impl Drop for Room {
-    fn drop(&mut self) {
-        self.task.abort();
+fn html_to_text(html: &str) -> String {
+    Document::from(html)
+        .find(Name("body"))
+        .next()
+        .map(|body| body.text())
+        .unwrap_or_default()
+}
+
+/// Parses how long to wait from a rate limited response, which says something like
+/// "You can perform this action again in 4 seconds".
+fn retry_after(response: &str) -> Option<Duration> {
+    let rest = &response[response.find("again in ")? + "again in ".len()..];
+    let seconds = rest.split_whitespace().next()?.parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// Someone who's been in a room recently.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RoomUser {
+    pub id: u64,
+    pub username: String,
+    /// Unknown until the user's info is fetched.
+    pub reputation: Option<u64>,
+    /// When the user was last seen in the room, since the Unix epoch.
+    pub last_seen: Option<Duration>,
+    pub moderator: bool,
+    /// Whether the user is an owner of the room.
+    pub owner: bool,
+}
+
+/// A user as described by the server's user info endpoint.
+#[derive(Deserialize, Debug)]
+struct UserInfo {
+    id: u64,
+    #[serde(default)]
+    reputation: Option<u64>,
+    #[serde(default)]
+    is_moderator: Option<bool>,
+    #[serde(default)]
+    is_owner: Option<bool>,
+}
+
+/// Fetches the reputation and roles of users, as seen from the given room.
+async fn fetch_user_info(client: &Client, fkey: &str, room_id: u64, ids: &[u64]) -> Result<Vec<UserInfo>, SeError> {
+    let ids = ids.iter().map(u64::to_string).collect::<Vec<String>>().join(",");
+    let response = client.post("https://chat.stackexchange.com/user/info")
+        .form(&[("ids", ids), ("roomId", room_id.to_string()), ("fkey", fkey.to_string())])
+        .send()
+        .await?
+        .json::<Value>()
+        .await?;
+    Ok(serde_json::from_value(response["users"].clone())?)
+}
+
+fn apply_user_info(users: &mut [RoomUser], info: &[UserInfo]) {
+    for info in info {
+        if let Some(user) = users.iter_mut().find(|user| user.id == info.id) {
+            user.reputation = info.reputation;
+            user.moderator = info.is_moderator.unwrap_or_default();
+            user.owner = info.is_owner.unwrap_or_default();
+        }
+    }
+}
+
+/// A message on a room's starboard.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StarredMessage {
+    pub id: u64,
+    pub stars: u64,
+    pub pinned: bool,
+    pub username: String,
+    pub text: String,
+}
+
+/// Checks the body of a response that is `"ok"` on success and an explanation otherwise.
+fn expect_ok(response: String) -> Result<(), SeError> {
+    if response.contains("too late") {
+        Err(SeError::EditWindowExpired)
+    } else if response.trim_matches('"') != "ok" {
+        Err(SeError::BadResponse(200, response))
+    } else {
+        Ok(())
     }
 }
 
@@ -191,6 +859,9 @@ pub struct Message {
     #[serde(rename = "message_id")]
     pub id: u64,
     pub content: String,
+    /// The message this one is a reply to, if any.
+    #[serde(default)]
+    pub parent_id: Option<u64>,
     pub user_id: u64,
     pub room_id: u64,
     #[serde(rename = "user_name")]
@@ -198,30 +869,88 @@ pub struct Message {
     #[serde(rename = "time_stamp")]
     #[serde_as(as = "serde_with::DurationSeconds<u64>")]
     pub timestamp: Duration,
+    #[serde(rename = "message_stars", default)]
+    pub stars: u64,
+    /// Stars given by room owners, which pin the message.
+    #[serde(rename = "message_owner_stars", default)]
+    pub owner_stars: u64,
+    /// Deleted messages are kept as a tombstone, with their content cleared.
+    #[serde(default)]
+    pub deleted: bool,
+    /// Whether the message pings us, by mentioning us or replying to us.
+    #[serde(skip)]
+    pub mentioned: bool,
+    #[serde(skip)]
+    pub state: MessageState,
+}
+
+/// Whether the server has a message yet.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub enum MessageState {
+    #[default]
+    Sent,
+    /// Shown locally while it's being sent. The content is the markdown we sent, not HTML.
+    Pending,
+    /// Sending it failed with the given error. Like pending messages, the content is markdown.
+    Failed(String),
+}
+
+impl Message {
+    pub fn is_pinned(&self) -> bool {
+        self.owner_stars > 0
+    }
+
+    /// The content with all HTML stripped.
+    pub fn text(&self) -> String {
+        html_to_text(&self.content)
+    }
+
+    /// Whether the message is still within the server's [`EDIT_WINDOW`].
+    pub fn is_editable(&self) -> bool {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        now.saturating_sub(self.timestamp) <= EDIT_WINDOW
+    }
 }
 
 impl TryFrom<ChatEventType> for Message {
     type Error = SeError;
 
     fn try_from(event: ChatEventType) -> Result<Self, Self::Error> {
-        if let ChatEventType::Message { event, content } = event {
+        if let ChatEventType::Message { event, content, parent_id, .. } = event {
             Ok(Message {
                 id: event.message_id,
                 content,
+                parent_id,
                 user_id: event.user_id,
                 room_id: event.room_id,
                 username: event.username,
                 timestamp: event.timestamp,
+                stars: 0,
+                owner_stars: 0,
+                deleted: false,
+                mentioned: false,
+                state: MessageState::Sent,
             })
         } else {
-            Err(SeError::ExpectedMessageEvent(event))
+            Err(SeError::ExpectedMessageEvent(Box::new(event)))
         }
     }
 }
 
 impl PartialEq for Message {
+    /// Messages are the same message if they have the same id, even if one was edited since.
     fn eq(&self, other: &Self) -> bool {
-        // the second case is for those fake messages that we send when the user sends a message
-        self.id == other.id || (self.content == other.content && self.username == other.username)
+        self.id == other.id
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rate_limit_wait() {
+        assert_eq!(retry_after("You can perform this action again in 4 seconds"), Some(Duration::from_secs(4)));
+        assert_eq!(retry_after("You can perform this action again in 1 second."), Some(Duration::from_secs(1)));
+        assert_eq!(retry_after("something else went wrong"), None);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,189 @@
+/// The most characters a single line message can have. Multi-line messages aren't limited.
+pub const MESSAGE_LIMIT: usize = 500;
+
+/// What to do with a message that's over [`MESSAGE_LIMIT`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum LongMessage {
+    /// Send it as one multi-line message in fixed font.
+    #[default]
+    FixedFont,
+    /// Split it between lines, or words if a line is too long, into several messages.
+    Split,
+    /// Send it as it is, for messages known to fit, like one that was already sent before.
+    Unchanged,
+}
+
+/// Whether the server would reject the message for being too long, with `limit` being how many
+/// characters are left for it, which is less than [`MESSAGE_LIMIT`] for a reply.
+pub fn is_too_long(msg: &str, limit: usize) -> bool {
+    !msg.contains('\n') && msg.chars().count() > limit
+}
+
+/// The prefix that makes a message a reply to `parent_id`, counted against the length limit.
+pub fn reply_prefix(parent_id: u64) -> String {
+    format!(":{} ", parent_id)
+}
+
+/// Turns a message into the messages to send for it, each of which has at most `limit`
+/// characters or is multi-line. Messages the server accepts are left as they are.
+pub fn fit_message(msg: &str, long: LongMessage, limit: usize) -> Vec<String> {
+    if !is_too_long(msg, limit) {
+        return vec![msg.to_string()];
+    }
+    match long {
+        LongMessage::FixedFont => vec![fixed_font(msg, limit)],
+        LongMessage::Split => split(msg, limit),
+        LongMessage::Unchanged => vec![msg.to_string()],
+    }
+}
+
+/// Like [`fit_message`], but for a reply to `parent_id`, which only the first message is.
+pub fn fit_reply(parent_id: u64, msg: &str, long: LongMessage) -> Vec<String> {
+    let prefix = reply_prefix(parent_id);
+    let mut parts = fit_message(msg, long, MESSAGE_LIMIT - prefix.len());
+    if let Some(first) = parts.first_mut() {
+        *first = if first.starts_with("    ") {
+            // the indentation that makes fixed font has to start its own line
+            format!("{}\n{}", prefix.trim_end(), first)
+        } else {
+            format!("{}{}", prefix, first)
+        };
+    }
+    parts
+}
+
+/// Indents every line by four spaces, which makes it fixed font. Long lines are wrapped so that
+/// even a single line message becomes multi-line.
+fn fixed_font(msg: &str, limit: usize) -> String {
+    msg.lines()
+        .flat_map(|line| split_words(line, limit))
+        .map(|line| format!("    {}", line))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn split(msg: &str, limit: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    for line in msg.lines().flat_map(|line| split_words(line, limit)) {
+        let len = line.chars().count();
+        if current_len > 0 && current_len + 1 + len > limit {
+            parts.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        if current_len > 0 {
+            current.push('\n');
+            current_len += 1;
+        }
+        current.push_str(&line);
+        current_len += len;
+    }
+    if !current.trim().is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+/// Splits a line between words into pieces of at most `limit` characters. Words that are longer
+/// than that on their own are cut wherever they have to be.
+fn split_words(line: &str, limit: usize) -> Vec<String> {
+    if line.chars().count() <= limit {
+        return vec![line.to_string()];
+    }
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    for word in line.split(' ') {
+        let mut word = word.to_string();
+        let mut len = word.chars().count();
+        if current_len > 0 && current_len + 1 + len > limit {
+            pieces.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        while len > limit {
+            let rest = word.split_off(word.char_indices().nth(limit).map_or(word.len(), |(i, _)| i));
+            pieces.push(word);
+            word = rest;
+            len -= limit;
+        }
+        if current_len > 0 {
+            current.push(' ');
+            current_len += 1;
+        }
+        current.push_str(&word);
+        current_len += len;
+    }
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_messages_that_fit() {
+        let msg = "a".repeat(MESSAGE_LIMIT);
+        assert_eq!(fit_message(&msg, LongMessage::FixedFont, MESSAGE_LIMIT), vec![msg.clone()]);
+        assert_eq!(fit_message(&msg, LongMessage::Split, MESSAGE_LIMIT), vec![msg]);
+    }
+
+    #[test]
+    fn leaves_long_multi_line_messages() {
+        let msg = format!("{}\n{}", "a".repeat(400), "b".repeat(400));
+        assert!(!is_too_long(&msg, MESSAGE_LIMIT));
+        assert_eq!(fit_message(&msg, LongMessage::Split, MESSAGE_LIMIT), vec![msg.clone()]);
+        assert_eq!(fit_message(&msg, LongMessage::FixedFont, MESSAGE_LIMIT), vec![msg]);
+    }
+
+    #[test]
+    fn fixed_font_wraps_into_indented_lines() {
+        let msg = format!("{} {}", "a".repeat(300), "b".repeat(300));
+        let parts = fit_message(&msg, LongMessage::FixedFont, MESSAGE_LIMIT);
+        assert_eq!(parts, vec![format!("    {}\n    {}", "a".repeat(300), "b".repeat(300))]);
+    }
+
+    #[test]
+    fn split_keeps_order_and_limit() {
+        let words = (0..200).map(|i| i.to_string()).collect::<Vec<String>>();
+        let msg = words.join(" ");
+        let parts = fit_message(&msg, LongMessage::Split, 100);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| part.chars().count() <= 100));
+        assert_eq!(parts.join(" "), msg);
+    }
+
+    #[test]
+    fn split_words_cuts_long_words() {
+        let word = "é".repeat(250);
+        let pieces = split_words(&format!("a {}", word), 100);
+        assert_eq!(pieces, vec![String::from("a"), "é".repeat(100), "é".repeat(100), "é".repeat(50)]);
+    }
+
+    #[test]
+    fn split_drops_nothing_but_whitespace() {
+        assert_eq!(split("", 10), Vec::<String>::new());
+        assert_eq!(split("   ", 10), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reply_prefix_counts_against_the_limit() {
+        let prefix = reply_prefix(123);
+        let msg = "a".repeat(MESSAGE_LIMIT - prefix.len() + 1);
+        let parts = fit_reply(123, &msg, LongMessage::Split);
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].starts_with(":123 "));
+        assert!(parts.iter().all(|part| part.chars().count() <= MESSAGE_LIMIT));
+        assert!(!parts[1].starts_with(':'));
+    }
+
+    #[test]
+    fn reply_prefix_goes_above_fixed_font() {
+        let msg = format!("{} {}", "a".repeat(300), "b".repeat(300));
+        let parts = fit_reply(123, &msg, LongMessage::FixedFont);
+        assert_eq!(parts, vec![format!(":123\n    {}\n    {}", "a".repeat(300), "b".repeat(300))]);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use crate::se::{markdown, Message, MessageState};
+
+/// Where the ids of pending messages start. Real message ids are nowhere near this, so pending
+/// messages always sort after every message the server knows about.
+const PENDING_IDS: u64 = 1 << 63;
+
+/// A change to a room's messages, as received from [`Room::subscribe`](crate::se::Room::subscribe).
+#[derive(Debug, Clone)]
+pub enum MessageUpdate {
+    /// A message was added, or changed in place.
+    Changed(Message),
+    /// A message was removed. Only pending messages are ever removed, once the server has them.
+    Removed(u64),
+}
+
+/// The loaded messages of a room, ordered by id and so also by when they were sent.
+///
+/// Messages we send are added as pending straight away under a local id, and replaced by the real
+/// message once [`MessageStore::confirm`] is given the id the server assigned it.
+#[derive(Debug)]
+pub struct MessageStore {
+    messages: BTreeMap<u64, Message>,
+    next_pending: u64,
+}
+
+impl MessageStore {
+    pub fn new() -> Self {
+        Self { messages: BTreeMap::new(), next_pending: PENDING_IDS }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Message> {
+        self.messages.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Message> {
+        self.messages.get_mut(&id)
+    }
+
+    /// Adds a message, replacing the one with the same id. Returns whether it was new.
+    pub fn insert(&mut self, message: Message) -> bool {
+        self.messages.insert(message.id, message).is_none()
+    }
+
+    /// Adds a message we're about to send, and returns it with the local id it was given.
+    pub fn add_pending(&mut self, user_id: u64, room_id: u64, username: &str, content: &str) -> Message {
+        let message = Message {
+            id: self.next_pending,
+            content: content.to_string(),
+            parent_id: None,
+            user_id,
+            room_id,
+            username: username.to_string(),
+            timestamp: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO),
+            stars: 0,
+            owner_stars: 0,
+            deleted: false,
+            mentioned: false,
+            state: MessageState::Pending,
+        };
+        self.next_pending += 1;
+        self.messages.insert(message.id, message.clone());
+        message
+    }
+
+    /// Replaces the pending message `local_id` with its real `id`. If the server's event for it
+    /// already arrived that is kept as is, and `None` is returned; otherwise the pending message
+    /// is kept under its real id until the event replaces it, and is returned. Its markdown is
+    /// rendered into HTML then, like the content of messages from the server.
+    pub fn confirm(&mut self, local_id: u64, id: u64) -> Option<Message> {
+        let mut message = self.messages.remove(&local_id)?;
+        if self.messages.contains_key(&id) {
+            return None;
+        }
+        message.id = id;
+        message.parent_id = markdown::split_reply(&message.content).0;
+        message.content = markdown::to_html(&message.content);
+        message.state = MessageState::Sent;
+        self.messages.insert(id, message.clone());
+        Some(message)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Message> {
+        self.messages.remove(&id)
+    }
+
+    /// Iterates over the messages from oldest to newest, with pending messages last.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item=&Message> {
+        self.messages.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_messages_sort_last() {
+        let mut store = MessageStore::new();
+        let pending = store.add_pending(1, 2, "me", "hi");
+        assert!(pending.id >= PENDING_IDS);
+        assert_eq!(store.iter().last().map(|message| message.id), Some(pending.id));
+    }
+
+    #[test]
+    fn confirm_renders_the_markdown() {
+        let mut store = MessageStore::new();
+        let pending = store.add_pending(1, 2, "me", ":5 **hi** <b>");
+        let confirmed = store.confirm(pending.id, 10).unwrap();
+        assert_eq!(confirmed.id, 10);
+        assert_eq!(confirmed.parent_id, Some(5));
+        assert_eq!(confirmed.content, "<b>hi</b> &lt;b&gt;");
+        assert_eq!(confirmed.state, MessageState::Sent);
+        assert!(store.get(pending.id).is_none());
+        assert!(store.get(10).is_some());
+    }
+
+    #[test]
+    fn confirm_keeps_the_server_event() {
+        let mut store = MessageStore::new();
+        let pending = store.add_pending(1, 2, "me", "hi");
+        let mut event = pending.clone();
+        event.id = 10;
+        event.content = String::from("from the server");
+        event.state = MessageState::Sent;
+        store.insert(event);
+        assert!(store.confirm(pending.id, 10).is_none());
+        assert_eq!(store.get(10).unwrap().content, "from the server");
+        assert!(store.get(pending.id).is_none());
+    }
+}
@@ -1,21 +1,31 @@
 use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
 
+use futures::future::join_all;
 use reqwest::Client;
 use reqwest_cookie_store::CookieStoreMutex;
 use select::document::Document;
 use select::predicate::{Attr, Class, Name, Predicate};
+use tokio::sync::watch;
+use tokio::time::timeout;
 
-use crate::se::{Room, RoomSpec, SeError};
+use crate::se::{Connection, ConnectionState, Room, RoomSpec, SeError};
 use crate::app::APP_USER_AGENT;
 
+/// How long closing the session waits for queued messages to be sent.
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct User {
     client: Client,
     cookies: Arc<CookieStoreMutex>,
     fkey: Option<String>,
     user_id: Option<u64>,
+    username: Option<String>,
+    connection: Option<Connection>,
     rooms: HashMap<u64, Room>,
-    pub current_room: Option<u64>,
+    current_room: Option<u64>,
 }
 
 impl User {
@@ -28,7 +38,7 @@ impl User {
             .cookie_provider(cookies.clone())
             .build()
             .unwrap();
-        Self { client, cookies, fkey: None, user_id: None, rooms: HashMap::new(), current_room: None }
+        Self { client, cookies, fkey: None, user_id: None, username: None, connection: None, rooms: HashMap::new(), current_room: None }
     }
 
     pub async fn login(&mut self, email: &str, password: &str) -> Result<(), SeError> {
@@ -42,46 +52,81 @@ impl User {
             self.load_profile(email, password, &fkey, host).await?;
         }
 
-        self.fkey = Some(
-            self.get_fkey("https://chat.stackexchange.com/chats/join/favorite")
-                .await
-                .map_err(|_| SeError::BadCredentials)?
-        );
-        self.user_id = Some(
-            self.get_id()
-                .await
-                .map_err(|_| SeError::BadCredentials)?
-        );
+        let fkey = self.get_fkey("https://chat.stackexchange.com/chats/join/favorite")
+            .await
+            .map_err(|_| SeError::BadCredentials)?;
+        self.connection = Some(Connection::new(self.client.clone(), fkey.clone()));
+        self.fkey = Some(fkey);
+        let (id, username) = self.get_profile()
+            .await
+            .map_err(|_| SeError::BadCredentials)?;
+        self.user_id = Some(id);
+        self.username = Some(username);
         Ok(())
     }
 
-    pub async fn join_room(&mut self, room_id: u64) -> Result<&Room, SeError> {
-        // if we already have the room, just return it
-        // have to use this workaround because of https://github.com/rust-lang/rfcs/blob/master/text/2094-nll.md#problem-case-3-conditional-control-flow-across-functions
-        if self.rooms.contains_key(&room_id) {
-            return Ok(self.rooms.get(&room_id).unwrap());
+    /// Starts joining a room. The returned future loads it without borrowing the user, so nothing
+    /// that shares the user has to wait on the network, and the room is then added with
+    /// [`User::add_room`].
+    pub fn join_room(&self, room_id: u64) -> Result<impl Future<Output=Result<Room, SeError>>, SeError> {
+        match (self.user_id, &self.username, &self.fkey) {
+            (Some(id), Some(username), Some(fkey)) =>
+                Ok(Room::new(self.cookies.clone(), fkey.clone(), id, username.clone(), room_id)),
+            _ => Err(SeError::BadCredentials),
         }
-        if let Some(id) = self.user_id {
-            if let Some(fkey) = &self.fkey {
-                if self.rooms.is_empty() {
-                    self.current_room = Some(room_id);
-                }
-                let room = Room::new(self.cookies.clone(), fkey.clone(), id, room_id).await;
-                self.rooms.insert(room_id, room);
-                return Ok(self.rooms.get(&room_id).unwrap());
-            }
+    }
+
+    /// Starts listening to a room loaded by [`User::join_room`]. The first room added becomes the
+    /// current one.
+    pub async fn add_room(&mut self, room: Room) -> &Room {
+        let room_id = room.get_id();
+        if let Some(connection) = &self.connection {
+            connection.add_room(room_id, room.event_handlers(), room.loaded_event_id()).await;
+        }
+        let first = self.rooms.is_empty();
+        self.rooms.insert(room_id, room);
+        if first {
+            self.set_current_room(room_id);
         }
-        Err(SeError::BadCredentials)
+        self.rooms.get(&room_id).unwrap()
     }
 
-    pub async fn leave_room(&mut self, room_id: u64) {
+    /// Leaves a room and stops listening to it. If it was the current room there is none after this.
+    pub async fn leave_room(&mut self, room_id: u64) -> Result<(), SeError> {
         let room = self.rooms.remove(&room_id);
         if let Some(room) = room {
             if self.current_room == Some(room_id) {
                 self.current_room = None;
             }
-            room.leave().await;
+            if let Some(connection) = &self.connection {
+                connection.remove_room(room_id).await;
+            }
+            room.leave().await?;
+        }
+        Ok(())
+    }
+
+    /// Ends the session. Messages still queued are given up to [`CLOSE_TIMEOUT`] to be sent, then
+    /// every room is left if `leave_rooms` is set, and finally the websocket is closed.
+    pub async fn close(mut self, leave_rooms: bool) -> Result<(), SeError> {
+        let _ = timeout(CLOSE_TIMEOUT, join_all(self.rooms.values().map(Room::sent))).await;
+        let mut result = Ok(());
+        if leave_rooms {
+            let room_ids = self.rooms.keys().copied().collect::<Vec<u64>>();
+            for room_id in room_ids {
+                // keep leaving the other rooms, but still report that one failed
+                if let Err(error) = self.leave_room(room_id).await {
+                    result = Err(error);
+                }
+            }
         }
+        // dropping the connection stops its task, which closes the socket
+        self.connection = None;
+        result
+    }
+
+    pub fn connection_state(&self) -> Option<watch::Receiver<ConnectionState>> {
+        self.connection.as_ref().map(Connection::state)
     }
 
     pub fn get_room(&self, room_id: u64) -> Option<&Room> {
@@ -92,6 +137,19 @@ impl User {
         self.rooms.values().collect()
     }
 
+    /// Switches to viewing a joined room, which marks it read. Does nothing if it isn't joined.
+    pub fn set_current_room(&mut self, room_id: u64) {
+        let room = match self.rooms.get(&room_id) {
+            Some(room) => room,
+            None => return,
+        };
+        room.set_viewed(true);
+        if let Some(previous) = self.current_room.filter(|&id| id != room_id).and_then(|id| self.rooms.get(&id)) {
+            previous.set_viewed(false);
+        }
+        self.current_room = Some(room_id);
+    }
+
     pub fn current_room(&self) -> Option<&Room> {
         if let Some(id) = self.current_room {
             return self.get_room(id);
@@ -209,7 +267,8 @@ impl User {
         Ok(())
     }
 
-    async fn get_id(&self) -> Result<u64, SeError> {
+    /// Gets our user id and display name from the link to our profile in the topbar.
+    async fn get_profile(&self) -> Result<(u64, String), SeError> {
         let response = self.client.get("https://chat.stackexchange.com/chats/join/favorite")
             .send()
             .await?
@@ -217,21 +276,20 @@ impl User {
             .await?;
 
         let document = Document::from(response.as_str());
-        let id_str = document.find(Class("topbar-menu-links"))
+        let link = document.find(Class("topbar-menu-links"))
             .next()
             .unwrap()
             .find(Name("a"))
             .next()
-            .unwrap()
-            .attr("href")
             .unwrap();
+        let id_str = link.attr("href").unwrap();
         let id = id_str
             .split("/")
             .nth(2)
             .unwrap()
             .parse();
         return if let Ok(id) = id {
-            Ok(id)
+            Ok((id, link.text().trim().to_string()))
         } else {
             if id_str.contains("login") {
                 Err(SeError::BadCredentials)
@@ -0,0 +1,664 @@
+use std::collections::HashMap;
+use std::env;
+use std::rc::Rc;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, TimeZone};
+use cursive::{Cursive, Printer, Rect, Vec2};
+use cursive::align::HAlign;
+use cursive::direction::Direction;
+use cursive::event::{Event, EventResult, Key, MouseEvent};
+use cursive::theme::{BaseColor, Color, ColorStyle, Effect, PaletteColor};
+use cursive::utils::markup::StyledString;
+use cursive::view::{CannotFocus, View};
+use cursive::views::{LinearLayout, TextView};
+
+use crate::render::render;
+use crate::se::{Message, MessageState};
+use crate::se::markdown;
+
+/// The width of the column left of a message, which shows whether it's selected or pings us.
+const GUTTER: usize = 2;
+
+/// Consecutive messages from the same user are grouped under one header, unless this much time
+/// passed between them.
+const GROUP_GAP: Duration = Duration::from_secs(5 * 60);
+
+/// How message times are shown.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Clock {
+    TwelveHour,
+    #[default]
+    TwentyFourHour,
+}
+
+impl Clock {
+    /// Reads the clock from the `LIGHTCHAT_CLOCK` environment variable, which can be `12` or `24`.
+    pub fn from_env() -> Self {
+        match env::var("LIGHTCHAT_CLOCK").as_deref() {
+            Ok("12") => Clock::TwelveHour,
+            _ => Clock::TwentyFourHour,
+        }
+    }
+
+    fn format(self, time: &DateTime<Local>) -> String {
+        match self {
+            Clock::TwelveHour => time.format("%l:%M %p").to_string(),
+            Clock::TwentyFourHour => time.format("%H:%M").to_string(),
+        }
+    }
+}
+
+/// When a message was sent, in the local time zone.
+fn local_time(message: &Message) -> DateTime<Local> {
+    Local.timestamp_opt(message.timestamp.as_secs() as i64, 0)
+        .earliest()
+        .unwrap_or_else(Local::now)
+}
+
+/// A single chat message, which can be selected and submitted to act on it.
+///
+/// Like in the web client, messages are grouped with the messages before them by the same user,
+/// and the first message of each day is preceded by its date. [`MessageList`] sets this up once it
+/// knows which message comes before.
+pub struct MessageView {
+    message: Message,
+    own: bool,
+    quote: Option<String>,
+    clock: Clock,
+    /// Whether the username is shown above the message, which it isn't if it's grouped.
+    header: bool,
+    /// Whether the date is shown above the message, because it's the first one of its day.
+    separator: bool,
+    content: LinearLayout,
+    on_submit: Option<SubmitCallback>,
+    on_char: Vec<(char, SubmitCallback)>,
+}
+
+type SubmitCallback = Rc<dyn Fn(&mut Cursive, &Message, bool)>;
+
+impl MessageView {
+    /// `own` is whether we sent the message, and `quote` is shown above it if it's a reply.
+    pub fn new(message: Message, own: bool, quote: Option<&str>, clock: Clock) -> Self {
+        let mut view = Self {
+            message,
+            own,
+            quote: quote.map(String::from),
+            clock,
+            header: true,
+            separator: false,
+            content: LinearLayout::vertical(),
+            on_submit: None,
+            on_char: Vec::new(),
+        };
+        view.build();
+        view
+    }
+
+    fn build(&mut self) {
+        let message = &self.message;
+        let mut content = LinearLayout::vertical();
+        let time = local_time(message);
+        if self.separator {
+            content.add_child(
+                TextView::new(StyledString::styled(
+                    format!("\u{2500}\u{2500} {} \u{2500}\u{2500}", time.format("%A, %-d %B %Y")),
+                    PaletteColor::Secondary,
+                )).h_align(HAlign::Center)
+            );
+        }
+        if self.header {
+            content.add_child(TextView::new(StyledString::styled(message.username.as_str(), Effect::Bold)));
+        }
+        if let Some(quote) = &self.quote {
+            content.add_child(TextView::new(StyledString::styled(format!("\u{21b3} {}", quote), Effect::Italic)));
+        }
+        let mut text = match &message.state {
+            _ if message.deleted => StyledString::styled("(removed)", Effect::Italic),
+            MessageState::Sent => render(&message.content),
+            // not rendered by the server yet, so this is still the markdown we sent
+            MessageState::Pending => {
+                let mut text = render(&markdown::to_html(&message.content));
+                text.append_styled(" (sending...)", Effect::Italic);
+                text
+            }
+            MessageState::Failed(error) => {
+                let mut text = render(&markdown::to_html(&message.content));
+                text.append_styled(format!(" (not sent: {})", error), Effect::Bold);
+                text
+            }
+        };
+        text.append(star_badge(message));
+        // the time is a column of its own, so that multi-line messages line up next to it
+        content.add_child(
+            LinearLayout::horizontal()
+                .child(TextView::new(StyledString::styled(
+                    format!("{} ", self.clock.format(&time)),
+                    PaletteColor::Secondary,
+                )))
+                .child(TextView::new(text))
+        );
+        self.content = content;
+    }
+
+    /// Groups the message with the one before it, if that was sent by the same user shortly
+    /// before. Returns whether that changed how the message is shown.
+    fn set_previous(&mut self, previous: Option<&Message>) -> bool {
+        let time = local_time(&self.message);
+        let separator = previous.is_none_or(|previous| local_time(previous).date_naive() != time.date_naive());
+        let header = separator || previous.is_some_and(|previous| {
+            previous.user_id != self.message.user_id
+                || self.message.timestamp.saturating_sub(previous.timestamp) > GROUP_GAP
+        });
+        if (header, separator) == (self.header, self.separator) {
+            return false;
+        }
+        self.header = header;
+        self.separator = separator;
+        self.build();
+        true
+    }
+
+    pub fn id(&self) -> u64 {
+        self.message.id
+    }
+
+    /// Shows `quote` above the message, for when the message it replies to is only known later.
+    fn set_quote(&mut self, quote: &str) {
+        self.quote = Some(quote.to_string());
+        self.build();
+    }
+
+    /// Sets the callback run when Enter is pressed on the message.
+    pub fn on_submit(mut self, callback: impl Fn(&mut Cursive, &Message, bool) + 'static) -> Self {
+        self.on_submit = Some(Rc::new(callback));
+        self
+    }
+
+    /// Sets the callback run when `c` is typed on the message.
+    pub fn on_char(mut self, c: char, callback: impl Fn(&mut Cursive, &Message, bool) + 'static) -> Self {
+        self.on_char.push((c, Rc::new(callback)));
+        self
+    }
+}
+
+impl View for MessageView {
+    fn draw(&self, printer: &Printer) {
+        // the selection takes precedence over showing that the message pings us
+        let gutter = if printer.focused {
+            Some(ColorStyle::highlight())
+        } else if self.message.mentioned {
+            Some(ColorStyle::front(Color::Light(BaseColor::Yellow)))
+        } else {
+            None
+        };
+        if let Some(color) = gutter {
+            printer.with_color(color, |printer| {
+                for y in 0..printer.size.y {
+                    printer.print((0, y), "\u{258c}");
+                }
+            });
+        }
+        self.content.draw(&printer.offset((GUTTER, 0)).focused(false));
+    }
+
+    fn layout(&mut self, size: Vec2) {
+        self.content.layout(size.saturating_sub((GUTTER, 0)));
+    }
+
+    fn required_size(&mut self, constraint: Vec2) -> Vec2 {
+        self.content.required_size(constraint.saturating_sub((GUTTER, 0))) + (GUTTER, 0)
+    }
+
+    fn on_event(&mut self, event: Event) -> EventResult {
+        let callback = match event {
+            Event::Key(Key::Enter) => self.on_submit.as_ref(),
+            Event::Char(c) => self.on_char.iter().find(|&&(key, _)| key == c).map(|(_, callback)| callback),
+            _ => None,
+        };
+        match callback {
+            Some(callback) => {
+                let callback = callback.clone();
+                let message = self.message.clone();
+                let own = self.own;
+                EventResult::with_cb(move |siv| callback(siv, &message, own))
+            }
+            None => EventResult::Ignored,
+        }
+    }
+
+    fn take_focus(&mut self, _: Direction) -> Result<EventResult, CannotFocus> {
+        Ok(EventResult::Consumed(None))
+    }
+}
+
+/// The star count shown after a message, and whether it's pinned.
+fn star_badge(message: &Message) -> StyledString {
+    let mut badge = StyledString::new();
+    if message.stars > 0 {
+        badge.append_styled(format!(" \u{2605}{}", message.stars), Effect::Bold);
+    }
+    if message.is_pinned() {
+        badge.append_styled(" (pinned)", Effect::Italic);
+    }
+    badge
+}
+
+/// A list of [`MessageView`]s kept in message order, which only lays out the ones on screen.
+///
+/// The list sticks to the newest message unless it's scrolled up or an older message is selected.
+pub struct MessageList {
+    views: Vec<MessageView>,
+    /// The index of each message in `views`, keyed by message id.
+    index: HashMap<u64, usize>,
+    /// The height of each view at `cached_width`, computed when it's first shown.
+    heights: Vec<Option<usize>>,
+    cached_width: usize,
+    selected: Option<usize>,
+    /// The index of the message shown at the bottom, or `None` to follow the newest one.
+    bottom: Option<usize>,
+    /// The messages on screen as of the last layout, as `(index, top row, height)`. The top row
+    /// is negative when only the end of the message fits.
+    visible: Vec<(usize, isize, usize)>,
+    size: Vec2,
+    on_top: Option<TopCallback>,
+    /// The oldest message when `on_top` was last called, so it's only called once per message.
+    requested_before: Option<u64>,
+}
+
+type TopCallback = Rc<dyn Fn(&mut Cursive, u64)>;
+
+impl MessageList {
+    pub fn new() -> Self {
+        Self {
+            views: Vec::new(),
+            index: HashMap::new(),
+            heights: Vec::new(),
+            cached_width: 0,
+            selected: None,
+            bottom: None,
+            visible: Vec::new(),
+            size: Vec2::zero(),
+            on_top: None,
+            requested_before: None,
+        }
+    }
+
+    /// Sets the callback run with the id of the oldest message when the top of the list is reached.
+    pub fn on_top(mut self, callback: impl Fn(&mut Cursive, u64) + 'static) -> Self {
+        self.on_top = Some(Rc::new(callback));
+        self
+    }
+
+    pub fn clear(&mut self) {
+        self.views.clear();
+        self.index.clear();
+        self.heights.clear();
+        self.selected = None;
+        self.bottom = None;
+        self.visible.clear();
+        self.requested_before = None;
+    }
+
+    /// Replaces the view of the same message if there is one, or inserts it in order otherwise.
+    pub fn upsert(&mut self, view: MessageView) {
+        if let Some(&i) = self.index.get(&view.id()) {
+            self.views[i] = view;
+            self.heights[i] = None;
+            self.regroup(i);
+            self.regroup(i + 1);
+            return;
+        }
+        let i = self.views.partition_point(|existing| existing.id() < view.id());
+        self.views.insert(i, view);
+        self.heights.insert(i, None);
+        if i + 1 < self.views.len() {
+            // inserted before other messages, so everything after it moved down by one
+            for (j, view) in self.views.iter().enumerate().skip(i) {
+                self.index.insert(view.id(), j);
+            }
+            self.selected = self.selected.map(|selected| if selected >= i { selected + 1 } else { selected });
+            self.bottom = self.bottom.map(|bottom| if bottom >= i { bottom + 1 } else { bottom });
+        } else {
+            self.index.insert(self.views[i].id(), i);
+        }
+        self.regroup(i);
+        self.regroup(i + 1);
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        let i = match self.index.remove(&id) {
+            Some(i) => i,
+            None => return,
+        };
+        self.views.remove(i);
+        self.heights.remove(i);
+        for (j, view) in self.views.iter().enumerate().skip(i) {
+            self.index.insert(view.id(), j);
+        }
+        self.regroup(i);
+        let last = self.views.len().checked_sub(1);
+        self.selected = match self.selected {
+            Some(selected) if selected > i => Some(selected - 1),
+            Some(selected) if selected == i => last.map(|last| selected.min(last)),
+            selected => selected,
+        };
+        self.bottom = match (self.bottom, last) {
+            (Some(bottom), Some(last)) if bottom > i || bottom > last => Some(bottom - 1),
+            (_, None) => None,
+            (bottom, _) => bottom,
+        };
+    }
+
+    /// Shows `quote` above the replies to `parent_id` that don't have one yet.
+    pub fn set_quote(&mut self, parent_id: u64, quote: &str) {
+        for (i, view) in self.views.iter_mut().enumerate() {
+            if view.message.parent_id == Some(parent_id) && view.quote.is_none() {
+                view.set_quote(quote);
+                self.heights[i] = None;
+            }
+        }
+    }
+
+    /// Groups the message at `i` with the one before it, after either of them changed.
+    fn regroup(&mut self, i: usize) {
+        if i >= self.views.len() {
+            return;
+        }
+        let (before, after) = self.views.split_at_mut(i);
+        if after[0].set_previous(before.last().map(|previous| &previous.message)) {
+            self.heights[i] = None;
+        }
+    }
+
+    fn reached_top(&mut self) -> EventResult {
+        let oldest = self.views[0].id();
+        match &self.on_top {
+            Some(callback) if self.requested_before != Some(oldest) => {
+                self.requested_before = Some(oldest);
+                let callback = callback.clone();
+                EventResult::with_cb(move |siv| callback(siv, oldest))
+            }
+            _ => EventResult::Ignored,
+        }
+    }
+
+    fn height(&mut self, i: usize) -> usize {
+        if let Some(height) = self.heights[i] {
+            return height;
+        }
+        let height = self.views[i].required_size(Vec2::new(self.cached_width, self.size.y)).y;
+        self.heights[i] = Some(height);
+        height
+    }
+
+    fn select(&mut self, i: usize) {
+        self.selected = Some(i);
+        // selecting the newest message goes back to following new ones
+        self.bottom = if i + 1 == self.views.len() { None } else { self.bottom.or(Some(self.views.len() - 1)) };
+    }
+
+    /// Scrolls by a number of messages, dropping the selection so it doesn't pull the list back.
+    fn scroll(&mut self, messages: isize) {
+        let last = self.views.len() - 1;
+        let bottom = self.bottom.unwrap_or(last) as isize + messages;
+        let bottom = bottom.clamp(0, last as isize) as usize;
+        self.selected = None;
+        self.bottom = if bottom == last { None } else { Some(bottom) };
+    }
+}
+
+impl View for MessageList {
+    fn draw(&self, printer: &Printer) {
+        for &(i, top, height) in self.visible.iter() {
+            let focused = self.selected == Some(i);
+            let printer = if top < 0 {
+                let cut = top.unsigned_abs();
+                printer.cropped((printer.size.x, height - cut))
+                    .content_offset((0, cut))
+                    .inner_size((printer.size.x, height))
+            } else {
+                printer.offset((0, top as usize)).cropped((printer.size.x, height))
+            };
+            self.views[i].draw(&printer.focused(focused));
+        }
+    }
+
+    fn layout(&mut self, size: Vec2) {
+        if size.x != self.cached_width {
+            self.cached_width = size.x;
+            self.heights.iter_mut().for_each(|height| *height = None);
+        }
+        self.size = size;
+        self.visible.clear();
+        if self.views.is_empty() {
+            return;
+        }
+        let last = self.views.len() - 1;
+        let mut bottom = self.bottom.unwrap_or(last).min(last);
+        if let Some(selected) = self.selected {
+            if selected > bottom {
+                bottom = selected;
+            } else {
+                // if the selection is above the screen, scroll up until it's the top message
+                let mut used = 0;
+                let mut i = bottom;
+                while i > selected && used < size.y {
+                    used += self.height(i);
+                    i -= 1;
+                }
+                if used + self.height(selected) > size.y {
+                    bottom = selected;
+                    let mut used = self.height(selected);
+                    while bottom < last && used + self.height(bottom + 1) <= size.y {
+                        bottom += 1;
+                        used += self.height(bottom);
+                    }
+                }
+            }
+            if self.bottom.is_some() || bottom != last {
+                self.bottom = Some(bottom);
+            }
+        }
+        let mut top = size.y as isize;
+        let mut i = bottom as isize;
+        while i >= 0 && top > 0 {
+            let height = self.height(i as usize);
+            top -= height as isize;
+            self.visible.push((i as usize, top, height));
+            i -= 1;
+        }
+        for &(i, _, height) in self.visible.iter() {
+            self.views[i].layout(Vec2::new(size.x, height));
+        }
+    }
+
+    fn required_size(&mut self, constraint: Vec2) -> Vec2 {
+        constraint
+    }
+
+    fn on_event(&mut self, event: Event) -> EventResult {
+        if self.views.is_empty() {
+            return EventResult::Ignored;
+        }
+        let last = self.views.len() - 1;
+        let page = self.visible.len().max(1);
+        // after scrolling with the mouse, keys start from the message at the bottom of the screen
+        let current = self.selected.unwrap_or_else(|| self.bottom.unwrap_or(last));
+        match event {
+            Event::Key(Key::Up) if self.selected.is_none() => self.select(current),
+            Event::Key(Key::Up) if current > 0 => self.select(current - 1),
+            Event::Key(Key::Down) if self.selected.is_none() => self.select(current),
+            Event::Key(Key::Down) if current < last => self.select(current + 1),
+            Event::Key(Key::Up) => return self.reached_top(),
+            Event::Key(Key::Down) => return EventResult::Ignored,
+            Event::Key(Key::PageUp) => self.select(current.saturating_sub(page)),
+            Event::Key(Key::PageDown) => self.select((current + page).min(last)),
+            Event::Key(Key::Home) => self.select(0),
+            Event::Key(Key::End) => self.select(last),
+            Event::Mouse { event: MouseEvent::WheelUp, .. } => {
+                self.scroll(-1);
+                if self.visible.iter().any(|&(i, _, _)| i == 0) {
+                    return self.reached_top();
+                }
+            }
+            Event::Mouse { event: MouseEvent::WheelDown, .. } => self.scroll(1),
+            event => {
+                return match self.selected {
+                    Some(selected) => self.views[selected].on_event(event),
+                    None => EventResult::Ignored,
+                };
+            }
+        }
+        EventResult::Consumed(None)
+    }
+
+    fn take_focus(&mut self, _: Direction) -> Result<EventResult, CannotFocus> {
+        if self.views.is_empty() {
+            return Err(CannotFocus);
+        }
+        if self.selected.is_none() {
+            self.select(self.views.len() - 1);
+        }
+        Ok(EventResult::Consumed(None))
+    }
+
+    fn important_area(&self, size: Vec2) -> Rect {
+        self.visible.iter()
+            .find(|&&(i, _, _)| Some(i) == self.selected)
+            .map(|&(_, top, height)| Rect::from_size((0, top.max(0) as usize), (size.x, height)))
+            .unwrap_or_else(|| Rect::from_size(Vec2::zero(), size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(id: u64) -> MessageView {
+        let message = Message {
+            id,
+            content: format!("message {}", id),
+            parent_id: None,
+            user_id: 1,
+            room_id: 1,
+            username: String::from("someone"),
+            timestamp: Duration::from_secs(1684029252 + id),
+            stars: 0,
+            owner_stars: 0,
+            deleted: false,
+            mentioned: false,
+            state: MessageState::Sent,
+        };
+        MessageView::new(message, false, None, Clock::default())
+    }
+
+    fn list(ids: &[u64]) -> MessageList {
+        let mut list = MessageList::new();
+        for &id in ids {
+            list.upsert(view(id));
+        }
+        list
+    }
+
+    fn assert_consistent(list: &MessageList) {
+        assert_eq!(list.index.len(), list.views.len());
+        assert_eq!(list.heights.len(), list.views.len());
+        for (i, view) in list.views.iter().enumerate() {
+            assert_eq!(list.index.get(&view.id()), Some(&i));
+        }
+        assert!(list.views.windows(2).all(|pair| pair[0].id() < pair[1].id()));
+        assert!(list.selected.is_none_or(|selected| selected < list.views.len()));
+        assert!(list.bottom.is_none_or(|bottom| bottom < list.views.len()));
+    }
+
+    fn selected_id(list: &MessageList) -> Option<u64> {
+        list.selected.map(|selected| list.views[selected].id())
+    }
+
+    fn bottom_id(list: &MessageList) -> Option<u64> {
+        list.bottom.map(|bottom| list.views[bottom].id())
+    }
+
+    #[test]
+    fn inserting_before_the_selection_keeps_it() {
+        let mut list = list(&[10, 20, 30]);
+        list.selected = Some(1);
+        list.bottom = Some(2);
+        list.upsert(view(5));
+        list.upsert(view(15));
+        assert_consistent(&list);
+        assert_eq!(selected_id(&list), Some(20));
+        assert_eq!(bottom_id(&list), Some(30));
+    }
+
+    #[test]
+    fn inserting_at_the_end_keeps_the_selection() {
+        let mut list = list(&[10, 20]);
+        list.selected = Some(1);
+        list.bottom = Some(1);
+        list.upsert(view(30));
+        assert_consistent(&list);
+        assert_eq!(selected_id(&list), Some(20));
+        assert_eq!(bottom_id(&list), Some(20));
+    }
+
+    #[test]
+    fn replacing_keeps_positions() {
+        let mut list = list(&[10, 20, 30]);
+        list.selected = Some(1);
+        list.upsert(view(20));
+        assert_consistent(&list);
+        assert_eq!(list.views.len(), 3);
+        assert_eq!(selected_id(&list), Some(20));
+    }
+
+    #[test]
+    fn removing_the_selected_message_selects_a_neighbour() {
+        let mut list = list(&[10, 20, 30]);
+        list.selected = Some(1);
+        list.bottom = Some(2);
+        list.remove(20);
+        assert_consistent(&list);
+        assert_eq!(selected_id(&list), Some(30));
+        assert_eq!(bottom_id(&list), Some(30));
+
+        list.selected = Some(1);
+        list.remove(30);
+        assert_consistent(&list);
+        assert_eq!(selected_id(&list), Some(10));
+        assert_eq!(bottom_id(&list), Some(10));
+    }
+
+    #[test]
+    fn removing_before_the_selection_keeps_it() {
+        let mut list = list(&[10, 20, 30]);
+        list.selected = Some(2);
+        list.bottom = Some(2);
+        list.remove(10);
+        assert_consistent(&list);
+        assert_eq!(selected_id(&list), Some(30));
+        assert_eq!(bottom_id(&list), Some(30));
+    }
+
+    #[test]
+    fn removing_the_last_message_clears_the_selection() {
+        let mut list = list(&[10]);
+        list.selected = Some(0);
+        list.bottom = Some(0);
+        list.remove(10);
+        assert_consistent(&list);
+        assert_eq!(list.selected, None);
+        assert_eq!(list.bottom, None);
+        list.remove(10);
+        assert_consistent(&list);
+    }
+
+    #[test]
+    fn only_bound_chars_are_consumed() {
+        let mut view = view(10).on_char('r', |_, _, _| {});
+        assert!(matches!(view.on_event(Event::Char('r')), EventResult::Consumed(Some(_))));
+        assert!(matches!(view.on_event(Event::Char('x')), EventResult::Ignored));
+        assert!(matches!(view.on_event(Event::Key(Key::Enter)), EventResult::Ignored));
+    }
+}